@@ -0,0 +1,109 @@
+#!/bin/sh
+#
+# Check that nothing reachable from the `.ram_func` section is in flash.
+#
+# Code in `.ram_func` runs while the QMI is in direct mode, when nothing in the
+# XIP address space can be read. A call from there to a function the compiler
+# chose not to inline, or a veneer or literal pointing into flash, would hang
+# or fault. This disassembles everything between the `__sram_func` and
+# `__eram_func` markers set up in `memory.x` and `rp235x_riscv.x`, and fails if
+# any instruction or literal refers to an address in the XIP space, or makes
+# an indirect call.
+#
+# Usage:
+#
+#   check-ram-funcs.sh ELF
+#   check-ram-funcs.sh --all DIR...
+#   check-ram-funcs.sh RUNNER [ARGS...] ELF
+#
+# The second form checks every binary cargo built into each DIR, such as
+# `target/thumbv8m.main-none-eabihf/release`, and is what CI runs after
+# `cargo build --bins`. The third form checks ELF, then runs the given command
+# on it, so this can be put in front of the runner in `.cargo/config.toml`.
+#
+# Needs `llvm-nm` and `llvm-objdump`, either on the PATH or from
+# `rustup component add llvm-tools`.
+
+set -eu
+
+usage() {
+    echo "usage: $0 [RUNNER [ARGS...]] ELF" >&2
+    echo "       $0 --all DIR..." >&2
+    exit 2
+}
+
+if [ $# -eq 0 ] || { [ "$1" = --all ] && [ $# -eq 1 ]; }; then
+    usage
+fi
+
+find_tool() {
+    if command -v "$1" >/dev/null 2>&1; then
+        echo "$1"
+        return
+    fi
+    for tool in "$(rustc --print sysroot)"/lib/rustlib/*/bin/"$1"; do
+        if [ -x "$tool" ]; then
+            echo "$tool"
+            return
+        fi
+    done
+    echo "$0: cannot find $1; try 'rustup component add llvm-tools'" >&2
+    exit 2
+}
+
+nm=$(find_tool llvm-nm)
+objdump=$(find_tool llvm-objdump)
+
+symbol() {
+    "$nm" "$1" | awk -v name="$2" '$3 == name { print "0x" $1 }'
+}
+
+check() {
+    start=$(symbol "$1" __sram_func)
+    stop=$(symbol "$1" __eram_func)
+    if [ -z "$start" ] || [ -z "$stop" ]; then
+        echo "$0: $1 has no __sram_func/__eram_func markers; is the linker script up to date?" >&2
+        exit 1
+    fi
+
+    # XIP addresses are 0x10000000 to 0x1fffffff: `0x1` then seven more
+    # digits. On RISC-V a far call is `auipc` then `jalr`, and the disassembly
+    # does not show the target, so any `jalr` other than a return is refused
+    # too.
+    if "$objdump" -D --no-show-raw-insn --start-address="$start" --stop-address="$stop" "$1" \
+        | grep -E -i '0x1[0-9a-f]{7}([^0-9a-f]|$)|[[:space:]]jalr[[:space:]]'; then
+        echo "$0: the code above, in $1's .ram_func, refers to flash or PSRAM" >&2
+        exit 1
+    fi
+}
+
+if [ "$1" = --all ]; then
+    shift
+    for dir in "$@"; do
+        found=
+        # Cargo puts binaries at the top of the profile directory, with no
+        # extension; everything else there is a `.d` file or a directory.
+        for elf in "$dir"/*; do
+            case ${elf##*/} in
+                *.*) continue ;;
+            esac
+            if [ -f "$elf" ] && [ -x "$elf" ]; then
+                check "$elf"
+                echo "$elf: ok"
+                found=1
+            fi
+        done
+        if [ -z "$found" ]; then
+            echo "$0: no binaries in $dir; build them first" >&2
+            exit 1
+        fi
+    done
+    exit 0
+fi
+
+for elf in "$@"; do :; done
+check "$elf"
+
+if [ $# -gt 1 ]; then
+    exec "$@"
+fi
@@ -39,6 +39,24 @@ SECTIONS {
 /* move .text to start /after/ the boot info */
 _stext = ADDR(.start_block) + SIZEOF(.start_block);
 
+SECTIONS {
+    /* ### Functions which run from RAM
+     *
+     * Code which runs while the QMI is in direct mode, when flash cannot be
+     * read. cortex-m-rt copies sections inserted after .data along with it,
+     * so this is loaded from flash at start-up like any other data.
+     * `check-ram-funcs.sh` uses the markers to check nothing in here calls
+     * out to flash.
+     */
+    .ram_func : ALIGN(4)
+    {
+        __sram_func = .;
+        *(.ram_func .ram_func.*);
+        . = ALIGN(4);
+        __eram_func = .;
+    } > RAM AT > FLASH
+} INSERT AFTER .data;
+
 SECTIONS {
     /* ### Picotool 'Binary Info' Entries
      *
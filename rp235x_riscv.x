@@ -159,6 +159,15 @@ SECTIONS
     PROVIDE(__global_pointer$ = . + 0x800);
     *(.sdata .sdata.* .sdata2 .sdata2.*);
     *(.data .data.*);
+    /* Functions which run while the QMI is in direct mode, when flash
+       cannot be read. They are loaded along with .data, and
+       `check-ram-funcs.sh` uses the markers to check nothing in here calls
+       out to flash. */
+    . = ALIGN(4);
+    __sram_func = .;
+    *(.ram_func .ram_func.*);
+    . = ALIGN(4);
+    __eram_func = .;
     . = ALIGN(32);
     _edata = .;
     __edata = .;
@@ -0,0 +1,299 @@
+//! # Embassy PSRAM Example
+//!
+//! Runs a PSRAM memory test on the embassy executor, alongside a task that
+//! blinks the LED.
+//!
+//! The memory test repeatedly fills the PSRAM, writes a pattern over it and
+//! reads it back, all by DMA, logging the throughput over defmt. While a
+//! transfer is in progress its task sleeps until the DMA interrupt, so the LED
+//! keeps blinking at 1 Hz. The blink task sleeps until a timer alarm, so when
+//! neither has anything to do the core waits for an interrupt.
+//!
+//! The PSRAM is set up for a Pimoroni Pico Plus 2; see `psram::boards` for
+//! other boards. The LED is on GP25, which may need adapting to your board.
+//!
+//! See the `Cargo.toml` file for Copyright and licence details.
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use critical_section::Mutex;
+use embassy_executor::Executor;
+use futures::task::AtomicWaker;
+use static_cell::StaticCell;
+
+// Ensure we halt the program on panic (if we don't mention this crate it won't
+// be linked)
+use panic_halt as _;
+
+// Log over RTT using defmt
+use defmt_rtt as _;
+
+// Alias for our HAL crate
+use rp235x_hal as hal;
+
+// Our PSRAM driver
+use rp235x_psram::psram;
+
+use embedded_hal::digital::OutputPin;
+use hal::dma::{Channel, DMAExt, CH0};
+use hal::gpio::bank0::{Gpio25, Gpio47};
+use hal::gpio::{FunctionSioOutput, Pin, PullDown};
+use hal::pac::interrupt;
+use hal::timer::{Alarm, Alarm0, CopyableTimer0, Instant};
+
+/// Tell the Boot ROM about our application
+#[link_section = ".start_block"]
+#[used]
+pub static IMAGE_DEF: hal::block::ImageDef = hal::block::ImageDef::secure_exe();
+
+/// External high-speed crystal on the Raspberry Pi Pico 2 board is 12 MHz.
+/// Adjust if your board has a different frequency
+const XTAL_FREQ_HZ: u32 = 12_000_000u32;
+
+/// Size of the SRAM buffer the memory test copies through.
+const CHUNK_SIZE: usize = 4096;
+
+type Psram = psram::Psram<Gpio47, PullDown>;
+type Timer = hal::Timer<CopyableTimer0>;
+type Led = Pin<Gpio25, FunctionSioOutput, PullDown>;
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+static PSRAM: StaticCell<Psram> = StaticCell::new();
+
+/// The buffer the memory test copies through, and the one it checks what it
+/// read against. These live outside the task, as a task's future must fit in
+/// the executor's 4 KiB task arena.
+static BUFFER: StaticCell<[u8; CHUNK_SIZE]> = StaticCell::new();
+static EXPECTED: StaticCell<[u8; CHUNK_SIZE]> = StaticCell::new();
+
+/// The alarm behind [`sleep_ms`], shared with its interrupt handler.
+static ALARM: Mutex<RefCell<Option<Alarm0<CopyableTimer0>>>> = Mutex::new(RefCell::new(None));
+
+/// The task waiting on [`ALARM`].
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Entry point to our bare-metal application.
+///
+/// The `#[hal::entry]` macro ensures the Cortex-M start-up code calls this function
+/// as soon as all global variables are initialised.
+///
+/// The function configures the RP2350 peripherals, then hands over to the
+/// embassy executor.
+#[hal::entry]
+fn main() -> ! {
+    // Grab our singleton objects
+    let mut pac = hal::pac::Peripherals::take().unwrap();
+
+    // Set up the watchdog driver - needed by the clock setup code
+    let mut watchdog = hal::Watchdog::new(pac.WATCHDOG);
+
+    // Configure the clocks
+    //
+    // The default is to generate a 125 MHz system clock
+    let clocks = hal::clocks::init_clocks_and_plls(
+        XTAL_FREQ_HZ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+
+    let mut timer = hal::Timer::new_timer0(pac.TIMER0, &mut pac.RESETS, &clocks);
+    let mut alarm = timer.alarm_0().unwrap();
+    alarm.enable_interrupt();
+    critical_section::with(|cs| *ALARM.borrow_ref_mut(cs) = Some(alarm));
+
+    // The single-cycle I/O block controls our GPIO pins
+    let sio = hal::Sio::new(pac.SIO);
+
+    // Set the pins to their default state
+    let pins = hal::gpio::Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut pac.RESETS,
+    );
+
+    let dma = pac.DMA.split(&mut pac.RESETS);
+
+    let psram = psram::Psram::for_board(
+        psram::boards::PimoroniPicoPlus2,
+        pac.QMI,
+        pac.XIP_CTRL,
+        pins.gpio47,
+        &clocks.system_clock,
+    );
+    let psram = match psram {
+        Ok(psram) => PSRAM.init(psram),
+        Err(e) => {
+            defmt::panic!("PSRAM initialisation failed: {}", e);
+        }
+    };
+    defmt::info!("PSRAM detected: {}", psram.info());
+
+    // Let the DMA wake the memory test, and the alarm wake the blink task
+    unsafe {
+        hal::arch::interrupt_unmask(hal::pac::Interrupt::DMA_IRQ_0);
+        hal::arch::interrupt_unmask(hal::pac::Interrupt::TIMER0_IRQ_0);
+        hal::arch::interrupt_enable();
+    }
+
+    let led = pins.gpio25.into_push_pull_output();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.must_spawn(blink(led, timer));
+        spawner.must_spawn(memory_test(psram, dma.ch0, timer));
+    })
+}
+
+#[interrupt]
+fn DMA_IRQ_0() {
+    psram::on_dma_irq0();
+}
+
+#[interrupt]
+fn TIMER0_IRQ_0() {
+    critical_section::with(|cs| {
+        if let Some(alarm) = ALARM.borrow_ref_mut(cs).as_mut() {
+            alarm.clear_interrupt();
+        }
+    });
+    ALARM_WAKER.wake();
+}
+
+/// Wait for `ms` milliseconds without holding up other tasks.
+///
+/// This uses the one alarm, so only one task may be sleeping at a time.
+async fn sleep_ms(timer: &Timer, ms: u64) {
+    let deadline = Instant::from_ticks(timer.get_counter().ticks() + ms * 1000);
+    poll_fn(|cx| {
+        if timer.get_counter() >= deadline {
+            return Poll::Ready(());
+        }
+        ALARM_WAKER.register(cx.waker());
+        critical_section::with(|cs| {
+            if let Some(alarm) = ALARM.borrow_ref_mut(cs).as_mut() {
+                // A deadline already passed raises the interrupt straight
+                // away, so this cannot miss it.
+                alarm.schedule_at(deadline).unwrap();
+            }
+        });
+        Poll::Pending
+    })
+    .await
+}
+
+/// Blink the LED at 1 Hz.
+#[embassy_executor::task]
+async fn blink(mut led: Led, timer: Timer) {
+    loop {
+        led.set_high().unwrap();
+        sleep_ms(&timer, 500).await;
+        led.set_low().unwrap();
+        sleep_ms(&timer, 500).await;
+    }
+}
+
+/// Test the whole PSRAM, forever.
+#[embassy_executor::task]
+async fn memory_test(psram: &'static Psram, ch: Channel<CH0>, timer: Timer) {
+    let size = psram.size();
+    // Safety: nothing else uses the PSRAM in this example.
+    let mut region = unsafe { psram.region(psram::PsramAlias::Uncached, 0..size) }.unwrap();
+    let mut dma = psram.dma(ch, timer);
+    let buf = BUFFER.init([0; CHUNK_SIZE]);
+    let expected = EXPECTED.init([0; CHUNK_SIZE]);
+
+    for pass in 0u32.. {
+        let stats = dma
+            .fill_async(&mut region, 0, size as usize, 0)
+            .await
+            .unwrap();
+        log_stats("fill", &stats);
+
+        let start = timer.get_counter();
+        for offset in (0..size as usize).step_by(CHUNK_SIZE) {
+            fill_pattern(buf, pass, offset);
+            dma.copy_to_async(&mut region, offset, &buf[..])
+                .await
+                .unwrap();
+        }
+        let micros = (timer.get_counter() - start).to_micros();
+        log_stats(
+            "write",
+            &psram::CopyStats {
+                bytes: size as usize,
+                micros,
+            },
+        );
+
+        let start = timer.get_counter();
+        let mut errors = 0;
+        for offset in (0..size as usize).step_by(CHUNK_SIZE) {
+            dma.copy_from_async(&region, offset, &mut buf[..])
+                .await
+                .unwrap();
+            fill_pattern(expected, pass, offset);
+            errors += buf
+                .iter()
+                .zip(&expected[..])
+                .filter(|(a, b)| a != b)
+                .count();
+        }
+        let micros = (timer.get_counter() - start).to_micros();
+        log_stats(
+            "read",
+            &psram::CopyStats {
+                bytes: size as usize,
+                micros,
+            },
+        );
+
+        if errors == 0 {
+            defmt::info!("pass {}: ok", pass);
+        } else {
+            defmt::error!("pass {}: {} bad bytes", pass, errors);
+        }
+    }
+}
+
+/// A pattern that differs for every pass and every address.
+fn fill_pattern(buf: &mut [u8], pass: u32, offset: usize) {
+    for (i, word) in buf.chunks_exact_mut(4).enumerate() {
+        let addr = (offset + i * 4) as u32;
+        word.copy_from_slice(&(addr ^ pass.rotate_left(16)).to_le_bytes());
+    }
+}
+
+fn log_stats(what: &str, stats: &psram::CopyStats) {
+    defmt::info!(
+        "{}: {} bytes in {} us ({} KiB/s)",
+        what,
+        stats.bytes,
+        stats.micros,
+        stats.bytes_per_second().unwrap_or(0) / 1024
+    );
+}
+
+/// Program metadata for `picotool info`
+#[link_section = ".bi_entries"]
+#[used]
+pub static PICOTOOL_ENTRIES: [hal::binary_info::EntryAddr; 5] = [
+    hal::binary_info::rp_cargo_bin_name!(),
+    hal::binary_info::rp_cargo_version!(),
+    hal::binary_info::rp_program_description!(c"Embassy PSRAM Example"),
+    hal::binary_info::rp_cargo_homepage_url!(),
+    hal::binary_info::rp_program_build_attribute!(),
+];
+
+// End of file
@@ -0,0 +1,7 @@
+//! Driver for QSPI PSRAM attached to the RP235x QMI on chip select 1.
+//!
+//! See the `Cargo.toml` file for Copyright and licence details.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod psram;
@@ -1,13 +1,17 @@
 //! # Alloc Example
 //!
-//! Uses alloc to create a Vec.
+//! Uses alloc to create a Vec, with small allocations served from SRAM and
+//! large ones from PSRAM.
 //!
 //! This will blink an LED attached to GP25, which is the pin the Pico uses for
 //! the on-board LED. It may need to be adapted to your particular board layout
-//! and/or pin assignment.
+//! and/or pin assignment. The PSRAM is set up for a Pimoroni Pico Plus 2; see
+//! `psram::boards` for other boards.
 //!
 //! While blinking the LED, it will continuously push to a `Vec`, which will
-//! eventually lead to a panic due to an out of memory condition.
+//! eventually lead to a panic due to an out of memory condition. The `Vec`
+//! moves to PSRAM once it outgrows the SRAM threshold, and the usage of each
+//! heap is logged as it goes.
 //!
 //! See the `Cargo.toml` file for Copyright and licence details.
 
@@ -17,17 +21,32 @@
 extern crate alloc;
 
 use alloc::vec::Vec;
-use embedded_alloc::Heap;
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+
+// Our PSRAM driver
+use rp235x_psram::psram;
+
+/// Allocations of this many bytes or more go to PSRAM.
+const PSRAM_THRESHOLD: usize = 1024;
+
+/// Size of the SRAM heap.
+const SRAM_HEAP_SIZE: usize = 32 * 1024;
+
+static mut SRAM_HEAP: [MaybeUninit<u8>; SRAM_HEAP_SIZE] = [MaybeUninit::uninit(); SRAM_HEAP_SIZE];
 
 #[global_allocator]
-static ALLOCATOR: Heap = Heap::empty();
+static ALLOCATOR: psram::DualHeap = psram::DualHeap::new(PSRAM_THRESHOLD);
 
 // Ensure we halt the program on panic (if we don't mention this crate it won't
 // be linked)
 use panic_halt as _;
 
+// Log over RTT using defmt
+use defmt_rtt as _;
+
 // Alias for our HAL crate
-use rp235x_hal::{self as hal, Clock};
+use rp235x_hal as hal;
 
 // Some things we need
 use embedded_hal::delay::DelayNs;
@@ -50,8 +69,6 @@ const XTAL_FREQ_HZ: u32 = 12_000_000u32;
 /// The function configures the RP2350 peripherals, then blinks the LED in an
 /// infinite loop where the duration indicates how many items were allocated.
 
-mod psram;
-
 #[hal::entry]
 fn main() -> ! {
 
@@ -92,17 +109,30 @@ fn main() -> ! {
     );
 
     //PSRAM INITIALIZATION
-    let _ = pins.gpio47.into_function::<hal::gpio::FunctionXipCs1>();
-    let psram_size = psram::psram_init(
-        clocks.peripheral_clock.freq().to_Hz(),
-        &pac.QMI,
-        &pac.XIP_CTRL,
+    let psram = psram::Psram::for_board(
+        psram::boards::PimoroniPicoPlus2,
+        pac.QMI,
+        pac.XIP_CTRL,
+        pins.gpio47,
+        &clocks.system_clock,
     );
-    
-    //USE PSRAM AS HEAP SPACE
-    {
-        const PSRAM_ADDRESS: usize = 0x11000000;
-        unsafe { ALLOCATOR.init(PSRAM_ADDRESS, psram_size as usize) }
+    let psram_size = match &psram {
+        Ok(psram) => {
+            defmt::info!("PSRAM detected: {}", psram.info());
+            psram.size()
+        }
+        Err(e) => {
+            defmt::error!("PSRAM initialisation failed: {}", e);
+            0
+        }
+    };
+
+    //SMALL ALLOCATIONS IN SRAM, LARGE ONES IN PSRAM
+    unsafe {
+        ALLOCATOR.init_sram(addr_of_mut!(SRAM_HEAP) as usize, SRAM_HEAP_SIZE);
+        if psram_size != 0 {
+            ALLOCATOR.init_psram(psram::PSRAM_BASE, psram_size as usize);
+        }
     }
 
     // Configure GPIO25 as an output
@@ -120,6 +150,7 @@ fn main() -> ! {
         led_pin.set_low().unwrap();
         timer.delay_ms(100 * len);
         xs.push(1);
+        defmt::info!("{} items: {}", xs.len(), ALLOCATOR.stats());
     }
 }
 
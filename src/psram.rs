@@ -1,191 +1,453 @@
-#[link_section = ".data"]
-#[inline(never)]
-pub fn detect_psram(qmi: &rp235x_hal::pac::QMI) -> u32 {
-    critical_section::with(|_cs| {
-        // Try and read the PSRAM ID via direct_csr.
-        qmi.direct_csr().write(|w| unsafe {
-            w.clkdiv().bits(30);
-            w.en().set_bit();
-            w
-        });
-
-        // Need to poll for the cooldown on the last XIP transfer to expire
-        // (via direct-mode BUSY flag) before it is safe to perform the first
-        // direct-mode operation
-        while qmi.direct_csr().read().busy().bit_is_set() {
-            rp235x_hal::arch::nop();
-        }
+pub mod boards;
+mod bus;
+pub mod cache;
+mod calibrate;
+mod collections;
+mod cursor;
+mod device;
+mod direct;
+mod dma;
+mod error;
+mod heap;
+pub mod lockout;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod power;
+mod region;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+mod storage;
+mod timing;
 
-        qmi.direct_csr().modify(|_r, w| w.assert_cs1n().set_bit());
+pub use bus::{QmiBus, Width, MAX_POLLS};
+pub use calibrate::{find_eye, Calibration};
+pub use collections::{PsramBox, PsramString, PsramVec};
+pub use cursor::PsramCursor;
+pub use device::{
+    CommandSet, IdFamily, PsramDevice, PsramId, PsramPart, SleepKind, SleepMode, APS_COMMANDS,
+    APS_HALF_SLEEP, APS_PARAMS, PARTS,
+};
+pub use dma::{on_dma_irq0, CopyStats, PsramDma};
+pub use error::PsramError;
+pub use heap::{DualHeap, DualHeapStats, HeapStats, PsramHeap};
+pub use power::{enter_sleep, exit_sleep};
+pub use region::{PsramAlias, PsramRegion};
+pub use storage::PsramStorage;
+pub use timing::{ChipParams, PsramTiming};
 
-        // Transmit as quad.
-        qmi.direct_tx().write(|w| unsafe {
-            w.oe().set_bit();
-            w.iwidth().q();
-            w.data().bits(0xf5);
-            w
-        });
+use rp235x_hal::clocks::{Clock, SystemClock};
+use rp235x_hal::gpio::{Function, FunctionXipCs1, Pin, PinId, PullType, ValidFunction};
+use rp235x_hal::pac::{QMI, XIP_CTRL};
+use rp235x_hal::sio::SioFifo;
 
-        while qmi.direct_csr().read().busy().bit_is_set() {
-            rp235x_hal::arch::nop();
-        }
+use lockout::FifoLockout;
 
-        let _ = qmi.direct_rx().read().bits();
-
-        qmi.direct_csr().modify(|_, w| {
-            w.assert_cs1n().clear_bit();
-            w
-        });
-
-        qmi.direct_csr().modify(|_, w| {
-            w.assert_cs1n().set_bit();
-            w
-        });
-
-        let mut kgd: u32 = 0;
-        let mut eid: u32 = 0;
-        for i in 0usize..7 {
-            if i == 0 {
-                qmi.direct_tx().write(|w| unsafe { w.bits(0x9f) });
-            } else {
-                qmi.direct_tx().write(|w| unsafe { w.bits(0xff) });
-            }
+/// Base address of the cached XIP window for chip select 1.
+pub const PSRAM_BASE: usize = 0x1100_0000;
 
-            while qmi.direct_csr().read().txempty().bit_is_clear() {
-                rp235x_hal::arch::nop();
-            }
+/// Base address of the uncached, non-allocating alias of [`PSRAM_BASE`].
+const PSRAM_NOCACHE_BASE: usize = 0x1500_0000;
 
-            while qmi.direct_csr().read().busy().bit_is_set() {
-                rp235x_hal::arch::nop();
-            }
+/// SCK divisor used while identifying the PSRAM, slow enough for any part.
+const DETECT_CLKDIV: u8 = 30;
+
+/// SCK divisor used for direct-mode commands and transfers once the PSRAM is
+/// identified. Direct mode has no RX delay, so this is kept slow.
+const COMMAND_CLKDIV: u8 = 10;
+
+/// How long a part takes to come out of reset (tRST), in nanoseconds. This is
+/// the longest of the parts we support, as we reset before identifying.
+const RESET_TIME_NS: u32 = 50;
+
+/// Pattern written to the PSRAM to check it has been configured correctly.
+const VERIFY_PATTERN: u32 = 0xA5C3_5A3C;
+
+/// The outcome of a successful [`psram_init`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PsramInfo {
+    /// The raw ID the device reported.
+    pub id: PsramId,
+    /// The table entry it matched.
+    pub part: &'static PsramPart,
+    /// The M1 timing currently programmed.
+    pub timing: PsramTiming,
+}
+
+impl PsramInfo {
+    /// Size of the PSRAM in bytes.
+    pub fn size(&self) -> u32 {
+        self.part.size
+    }
 
-            if i == 5 {
-                kgd = qmi.direct_rx().read().bits();
-            } else if i == 6 {
-                eid = qmi.direct_rx().read().bits();
-            } else {
-                let _ = qmi.direct_rx().read().bits();
+    /// Base address of the PSRAM in the cached XIP window.
+    pub fn base_address(&self) -> usize {
+        PSRAM_BASE
+    }
+}
+
+/// The chip select pin type for a PSRAM on XIP chip select 1.
+///
+/// See [`boards`] for which GPIOs can be used.
+pub type CsPin<I, P> = Pin<I, FunctionXipCs1, P>;
+
+/// An initialised PSRAM.
+///
+/// This owns the QMI and XIP_CTRL peripherals, so nothing else can
+/// reconfigure them behind its back, and the pin used for chip select.
+pub struct Psram<I: PinId, P: PullType> {
+    qmi: QMI,
+    xip: XIP_CTRL,
+    cs: CsPin<I, P>,
+    info: PsramInfo,
+    clock_hz: u32,
+    asleep: bool,
+    lockout: Option<FifoLockout>,
+}
+
+impl<I: PinId, P: PullType> Psram<I, P> {
+    /// Detect and configure the PSRAM, see [`psram_init`].
+    ///
+    /// The other core must not be running yet; if it is, use
+    /// [`Psram::new_with_lockout`].
+    pub fn new(
+        qmi: QMI,
+        xip: XIP_CTRL,
+        cs: CsPin<I, P>,
+        system_clock: &SystemClock,
+    ) -> Result<Self, PsramError> {
+        Self::init(qmi, xip, cs, None, system_clock)
+    }
+
+    /// Detect and configure the PSRAM with the other core parked, and keep
+    /// parking it whenever needed, see [`Psram::set_lockout`].
+    pub fn new_with_lockout(
+        qmi: QMI,
+        xip: XIP_CTRL,
+        cs: CsPin<I, P>,
+        fifo: SioFifo,
+        system_clock: &SystemClock,
+    ) -> Result<Self, PsramError> {
+        Self::init(qmi, xip, cs, Some(FifoLockout::new(fifo)), system_clock)
+    }
+
+    fn init(
+        mut qmi: QMI,
+        xip: XIP_CTRL,
+        cs: CsPin<I, P>,
+        mut lockout: Option<FifoLockout>,
+        system_clock: &SystemClock,
+    ) -> Result<Self, PsramError> {
+        let info = lockout::parked(lockout.as_mut(), || {
+            psram_init(system_clock, &mut qmi, &xip)
+        })?;
+        Ok(Psram {
+            qmi,
+            xip,
+            cs,
+            info,
+            clock_hz: system_clock.freq().to_Hz(),
+            asleep: false,
+            lockout,
+        })
+    }
+
+    /// Detect and configure the PSRAM on a known [`boards::Board`].
+    ///
+    /// `cs` is the board's PSRAM chip select pin in any function; it is
+    /// switched to [`FunctionXipCs1`] here.
+    pub fn for_board<B, F>(
+        _board: B,
+        qmi: QMI,
+        xip: XIP_CTRL,
+        cs: Pin<I, F, P>,
+        system_clock: &SystemClock,
+    ) -> Result<Self, PsramError>
+    where
+        B: boards::Board<CsPin = I>,
+        I: ValidFunction<FunctionXipCs1>,
+        F: Function,
+    {
+        defmt::debug!("PSRAM board: {}", B::NAME);
+        Self::new(qmi, xip, cs.into_function(), system_clock)
+    }
+
+    /// Reset the PSRAM and set it up again, to recover it if it stops
+    /// responding.
+    ///
+    /// The contents of the PSRAM should be treated as lost, and nothing may
+    /// access it while this runs. A sleeping PSRAM is woken first.
+    pub fn reset(&mut self) -> Result<(), PsramError> {
+        self.parked(|psram| {
+            if psram.asleep {
+                psram.wake()?;
             }
+            // Copy the table entry out of flash before entering direct mode.
+            let part: PsramPart = *psram.info.part;
+            reset_psram(&mut psram.qmi)?;
+            configure_qpi(&mut psram.qmi, &part, &psram.info.timing)
+        })
+    }
+
+    /// Release the peripherals, chip select pin and lockout FIFO, if one was
+    /// set.
+    ///
+    /// The QMI is left configured, so the PSRAM remains mapped, unless it is
+    /// asleep.
+    pub fn free(self) -> (QMI, XIP_CTRL, CsPin<I, P>, Option<SioFifo>) {
+        let fifo = self.lockout.map(FifoLockout::free);
+        (self.qmi, self.xip, self.cs, fifo)
+    }
+
+    /// What was detected, and how it is configured.
+    pub fn info(&self) -> &PsramInfo {
+        &self.info
+    }
+
+    /// Size of the PSRAM in bytes.
+    pub fn size(&self) -> u32 {
+        self.info.size()
+    }
+
+    /// Base address of the PSRAM in the cached XIP window.
+    pub fn base_address(&self) -> usize {
+        self.info.base_address()
+    }
+
+    /// The clk_sys frequency the current timing was computed for.
+    pub fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    /// Program the M1 timing for a new clk_sys frequency.
+    ///
+    /// Call this once clk_sys has been reconfigured. If the PSRAM may be
+    /// accessed while the clock is changing, call
+    /// [`Psram::prepare_clock_change`] first.
+    pub fn set_clock(&mut self, system_clock: &SystemClock) -> Result<PsramTiming, PsramError> {
+        let sys_clock_hz = system_clock.freq().to_Hz();
+        let timing = PsramTiming::compute(sys_clock_hz, &self.info.part.params)?;
+        self.parked(|psram| write_m1_timing(&mut psram.qmi, &timing))?;
+        self.info.timing = timing;
+        self.clock_hz = sys_clock_hz;
+        Ok(timing)
+    }
+
+    /// Get ready for clk_sys to change to `new_sys_clock_hz`.
+    ///
+    /// This programs an M1 timing which is valid at both the current and the
+    /// new frequency, so the PSRAM stays usable while `hal::clocks` switches
+    /// over. Once the switch is done, call [`Psram::set_clock`]:
+    ///
+    /// ```ignore
+    /// psram.prepare_clock_change(300_000_000)?;
+    /// clocks.system_clock.configure_clock(&pll_sys, 300.MHz())?;
+    /// psram.set_clock(&clocks.system_clock)?;
+    /// ```
+    pub fn prepare_clock_change(
+        &mut self,
+        new_sys_clock_hz: u32,
+    ) -> Result<PsramTiming, PsramError> {
+        let next = PsramTiming::compute(new_sys_clock_hz, &self.info.part.params)?;
+        let timing = self.info.timing.covering(&next);
+        self.parked(|psram| write_m1_timing(&mut psram.qmi, &timing))?;
+        self.info.timing = timing;
+        Ok(timing)
+    }
+}
+
+// Direct mode
+//
+// While the QMI is in direct mode, nothing in the XIP space can be read,
+// including the code in flash. So each operation is split in two: a function
+// in flash which masks interrupts with a critical section, and a function in
+// `.ram_func` which enters direct mode, does its work, and leaves direct mode
+// again. The latter may only call `#[inline(always)]` code, and
+// `check-ram-funcs.sh` fails the build if anything it reaches is in flash.
+
+/// Rewrite the clock-dependent fields of M1_TIMING.
+///
+/// Direct mode is enabled while we do this: that stalls any new XIP access,
+/// and lets us wait on BUSY for one already in flight to finish.
+fn write_m1_timing<B: QmiBus>(bus: &mut B, timing: &PsramTiming) -> Result<(), PsramError> {
+    critical_section::with(|_cs| write_m1_timing_direct(bus, timing))
+}
+
+#[link_section = ".ram_func"]
+#[inline(never)]
+fn write_m1_timing_direct<B: QmiBus>(bus: &mut B, timing: &PsramTiming) -> Result<(), PsramError> {
+    bus.enable_direct(COMMAND_CLKDIV, false);
+
+    let result = bus.wait_idle();
+    if result.is_ok() {
+        bus.update_m1_timing(timing);
+    }
+
+    bus.disable_direct();
+    result
+}
+
+pub fn detect_psram<B: QmiBus>(bus: &mut B) -> Result<PsramId, PsramError> {
+    critical_section::with(|_cs| detect_psram_direct(bus))
+}
+
+#[link_section = ".ram_func"]
+#[inline(never)]
+fn detect_psram_direct<B: QmiBus>(bus: &mut B) -> Result<PsramId, PsramError> {
+    // Try and read the PSRAM ID via direct_csr.
+    bus.enable_direct(DETECT_CLKDIV, false);
+
+    let result = read_id(bus);
+
+    bus.disable_direct();
+    result
+}
+
+/// Reset the PSRAM, leaving it in SPI mode.
+///
+/// This gets the part back to a known state after a brown-out or a debugger
+/// reset, which may have left it in QPI mode or part way through a burst. The
+/// contents of the PSRAM should be treated as lost.
+pub fn reset_psram<B: QmiBus>(bus: &mut B) -> Result<(), PsramError> {
+    critical_section::with(|_cs| reset_psram_direct(bus))
+}
+
+#[link_section = ".ram_func"]
+#[inline(never)]
+fn reset_psram_direct<B: QmiBus>(bus: &mut B) -> Result<(), PsramError> {
+    bus.enable_direct(DETECT_CLKDIV, false);
+
+    let mut result = bus.wait_idle();
+    if result.is_ok() {
+        result = send_reset(bus);
+    }
+
+    bus.disable_direct();
+    result
+}
+
+/// Send Reset Enable then Reset, first as QPI and then as SPI, and wait for
+/// the part to come out of reset. Direct mode must already be enabled.
+///
+/// A part in QPI mode resets on both pairs: the first returns it to SPI mode,
+/// and the second, now understood, resets it again. A part in SPI mode sees
+/// only two clocks for each QPI command, which it discards when CS is
+/// deasserted, and then resets on the second pair. We don't know
+/// what the part is yet, but every part we support uses the same commands.
+#[inline(always)]
+fn send_reset<B: QmiBus>(bus: &mut B) -> Result<(), PsramError> {
+    for width in [Width::Quad, Width::Single] {
+        for command in [APS_COMMANDS.reset_enable, APS_COMMANDS.reset] {
+            bus.select(true);
+            bus.send(width, command)?;
+            bus.select(false);
         }
+    }
+    bus.delay_ns(RESET_TIME_NS);
+    Ok(())
+}
 
-        qmi.direct_csr().modify(|_, w| {
-            w.assert_cs1n().clear_bit();
-            w.en().clear_bit();
-            w
-        });
-        let mut param_size: u32 = 0;
-        if kgd == 0x5D {
-            param_size = 1024 * 1024;
-            let size_id = eid >> 5;
-            if eid == 0x26 || size_id == 2 {
-                param_size *= 8;
-            } else if size_id == 0 {
-                param_size *= 2;
-            } else if size_id == 1 {
-                param_size *= 4;
-            }
+/// Reset the PSRAM and read its ID. Direct mode must already be enabled.
+#[inline(always)]
+fn read_id<B: QmiBus>(bus: &mut B) -> Result<PsramId, PsramError> {
+    // Need to poll for the cooldown on the last XIP transfer to expire
+    // (via direct-mode BUSY flag) before it is safe to perform the first
+    // direct-mode operation
+    bus.wait_idle()?;
+
+    send_reset(bus)?;
+
+    bus.select(true);
+
+    // Read ID is the command, a 24-bit address, then the ID bytes.
+    let mut raw = [0u8; 8];
+    for i in 0usize..12 {
+        let data = if i == 0 { APS_COMMANDS.read_id } else { 0xFF };
+        let value = bus.transfer(Width::Single, data)?;
+        if i >= 4 {
+            raw[i - 4] = value;
         }
-        param_size
+    }
+
+    Ok(PsramId { raw })
+}
+
+pub fn psram_init<B: QmiBus>(
+    system_clock: &SystemClock,
+    bus: &mut B,
+    xip: &XIP_CTRL,
+) -> Result<PsramInfo, PsramError> {
+    // QMI is clocked from clk_sys, so that is what the timing is derived from.
+    let clock_hz = system_clock.freq().to_Hz();
+    let psram_id = detect_psram(bus)?;
+    let psram_part = psram_id.device().known()?;
+
+    // Take a copy of the table entry now, the table itself lives in flash
+    // which is not accessible once we enter direct mode.
+    let part: PsramPart = *psram_part;
+
+    let timing = PsramTiming::compute(clock_hz, &part.params)?;
+
+    configure_qpi(bus, &part, &timing)?;
+
+    // Enable writes to PSRAM
+    xip.ctrl().modify(|_, w| w.writable_m1().set_bit());
+
+    verify_psram(part.size)?;
+
+    Ok(PsramInfo {
+        id: psram_id,
+        part: psram_part,
+        timing,
     })
 }
 
-#[link_section = ".data"]
+/// Switch the PSRAM to QPI mode and program M1 to access it.
+///
+/// `part` and `timing` must be in RAM, not in the flash-resident [`PARTS`]
+/// table.
+pub fn configure_qpi<B: QmiBus>(
+    bus: &mut B,
+    part: &PsramPart,
+    timing: &PsramTiming,
+) -> Result<(), PsramError> {
+    critical_section::with(|_cs| configure_qpi_direct(bus, part, timing))
+}
+
+#[link_section = ".ram_func"]
 #[inline(never)]
-pub fn psram_init(
-    clock_hz: u32,
-    qmi: &rp235x_hal::pac::QMI,
-    xip: &rp235x_hal::pac::XIP_CTRL,
-) -> u32 {
-    let psram_size = detect_psram(qmi);
-
-    if psram_size == 0 {
-        return 0;
-    }
-
-    // Set PSRAM timing for APS6404
-    //
-    // Using an rxdelay equal to the divisor isn't enough when running the APS6404 close to 133MHz.
-    // So: don't allow running at divisor 1 above 100MHz (because delay of 2 would be too late),
-    // and add an extra 1 to the rxdelay if the divided clock is > 100MHz (i.e. sys clock > 200MHz).
-    const MAX_PSRAM_FREQ: u32 = 133_000_000;
-
-    let mut divisor: u32 = (clock_hz + MAX_PSRAM_FREQ - 1) / MAX_PSRAM_FREQ;
-    if divisor == 1 && clock_hz > 100_000_000 {
-        divisor = 2;
-    }
-    let mut rxdelay: u32 = divisor;
-    if clock_hz / divisor > 100_000_000 {
-        rxdelay += 1;
-    }
-
-    // - Max select must be <= 8us.  The value is given in multiples of 64 system clocks.
-    // - Min deselect must be >= 18ns.  The value is given in system clock cycles - ceil(divisor / 2).
-    let clock_period_fs: u64 = 1_000_000_000_000_000_u64 / u64::from(clock_hz);
-    let max_select: u8 = ((125 * 1_000_000) / clock_period_fs) as u8;
-    let min_deselect: u32 = ((18 * 1_000_000 + (clock_period_fs - 1)) / clock_period_fs
-        - u64::from(divisor + 1) / 2) as u32;
-
-    qmi.direct_csr().write(|w| unsafe {
-        w.clkdiv().bits(10);
-        w.en().set_bit();
-        w.auto_cs1n().set_bit();
-        w
-    });
-
-    while qmi.direct_csr().read().busy().bit_is_set() {
-        rp235x_hal::arch::nop();
-    }
-
-    qmi.direct_tx().write(|w| unsafe {
-        w.nopush().set_bit();
-        w.bits(0x35);
-        w
-    });
-
-    while qmi.direct_csr().read().busy().bit_is_set() {
-        rp235x_hal::arch::nop();
-    }
-
-    qmi.m1_timing().write(|w| unsafe {
-        w.cooldown().bits(1);
-        w.pagebreak()._1024();
-        w.max_select().bits(max_select as u8);
-        w.min_deselect().bits(min_deselect as u8);
-        w.rxdelay().bits(rxdelay as u8);
-        w.clkdiv().bits(divisor as u8);
-        w
-    });
-
-    // // Set PSRAM commands and formats
-    qmi.m1_rfmt().write(|w| {
-        w.prefix_width().q();
-        w.addr_width().q();
-        w.suffix_width().q();
-        w.dummy_width().q();
-        w.data_width().q();
-        w.prefix_len()._8();
-        w.dummy_len()._24();
-        w
-    });
-
-    qmi.m1_rcmd().write(|w| unsafe { w.bits(0xEB) });
-
-    qmi.m1_wfmt().write(|w| {
-        w.prefix_width().q();
-        w.addr_width().q();
-        w.suffix_width().q();
-        w.dummy_width().q();
-        w.data_width().q();
-        w.prefix_len()._8();
-        w
-    });
-
-    qmi.m1_wcmd().write(|w| unsafe { w.bits(0x38) });
+fn configure_qpi_direct<B: QmiBus>(
+    bus: &mut B,
+    part: &PsramPart,
+    timing: &PsramTiming,
+) -> Result<(), PsramError> {
+    bus.enable_direct(COMMAND_CLKDIV, true);
+
+    let result = bus.send(Width::Single, part.commands.enter_qpi);
+    if result.is_ok() {
+        bus.configure_m1(part, timing);
+    }
 
     // Disable direct mode
-    qmi.direct_csr().write(|w| unsafe { w.bits(0) });
+    bus.disable_direct();
+    result
+}
 
-    // Enable writes to PSRAM
-    xip.ctrl().modify(|_, w| w.writable_m1().set_bit());
-    psram_size
+/// Check that the first and last words of the PSRAM hold what we write to
+/// them, through the uncached alias so the XIP cache cannot hide a failure.
+fn verify_psram(size: u32) -> Result<(), PsramError> {
+    for offset in [0, size - 4] {
+        let ptr = (PSRAM_NOCACHE_BASE + offset as usize) as *mut u32;
+        for pattern in [VERIFY_PATTERN, !VERIFY_PATTERN] {
+            // Safety: the M1 window has just been configured to cover `size`
+            // bytes and nothing else is using it yet.
+            let read = unsafe {
+                ptr.write_volatile(pattern);
+                ptr.read_volatile()
+            };
+            if read != pattern {
+                return Err(PsramError::VerifyFailed { offset });
+            }
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,64 @@
+//! Boards with PSRAM on XIP chip select 1.
+//!
+//! XIP_CS1 is available on GPIO0, GPIO8, GPIO19 and (RP2350B only) GPIO47.
+//! The HAL only lets a pin be switched to [`FunctionXipCs1`] if it is one of
+//! those, so [`Psram::new`](super::Psram::new) accepts any of them. A
+//! [`Board`] goes one step further and ties the pin to the board layout, so
+//! passing the wrong GPIO is a compile error:
+//!
+//! ```ignore
+//! let psram = Psram::for_board(
+//!     boards::AdafruitFeatherRp2350,
+//!     pac.QMI,
+//!     pac.XIP_CTRL,
+//!     pins.gpio8,
+//!     &clocks.system_clock,
+//! )?;
+//! ```
+
+use rp235x_hal::gpio::{bank0, FunctionXipCs1, PinId, ValidFunction};
+
+/// A board with PSRAM wired to XIP chip select 1.
+pub trait Board {
+    /// The GPIO wired to the PSRAM chip select.
+    type CsPin: PinId + ValidFunction<FunctionXipCs1>;
+
+    /// Name of the board, for logging.
+    const NAME: &'static str;
+}
+
+/// Pimoroni Pico Plus 2 (RP2350B, APS6404L).
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct PimoroniPicoPlus2;
+
+impl Board for PimoroniPicoPlus2 {
+    type CsPin = bank0::Gpio47;
+    const NAME: &'static str = "Pimoroni Pico Plus 2";
+}
+
+/// Adafruit Feather RP2350 with the optional PSRAM fitted (RP2350A).
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct AdafruitFeatherRp2350;
+
+impl Board for AdafruitFeatherRp2350 {
+    type CsPin = bank0::Gpio8;
+    const NAME: &'static str = "Adafruit Feather RP2350";
+}
+
+/// SparkFun Pro Micro RP2350 (RP2350A).
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct SparkFunProMicroRp2350;
+
+impl Board for SparkFunProMicroRp2350 {
+    type CsPin = bank0::Gpio19;
+    const NAME: &'static str = "SparkFun Pro Micro RP2350";
+}
+
+/// SparkFun Thing Plus RP2350 (RP2350A).
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct SparkFunThingPlusRp2350;
+
+impl Board for SparkFunThingPlusRp2350 {
+    type CsPin = bank0::Gpio8;
+    const NAME: &'static str = "SparkFun Thing Plus RP2350";
+}
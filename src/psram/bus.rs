@@ -0,0 +1,269 @@
+//! Access to the QMI in direct mode, and to the M1 configuration registers.
+//!
+//! The driver talks to the hardware through the [`QmiBus`] trait, so the same
+//! code can run against the `mock` and `sim` buses off target. The
+//! implementation for [`QMI`] is called while the QMI is in direct mode, when
+//! flash is not accessible, so every method is inlined into its caller in
+//! `.ram_func`, and nothing it calls may be out of line.
+
+use rp235x_hal::pac::QMI;
+
+use super::{PsramError, PsramPart, PsramTiming};
+
+/// How many times a direct-mode status flag is polled before giving up.
+///
+/// This is a count of polls, not of cycles or time. The bus does not know
+/// clk_sys, but it does not need to: SCK is divided down from clk_sys, so the
+/// slowest transfer we issue (one byte at a clock divisor of 30) always takes
+/// about 480 clk_sys cycles, and each poll takes at least a few. So this is
+/// generous at any clock, while still turning a stuck bus into an error
+/// within a millisecond or so at the clocks the RP2350 runs at. Bus wait
+/// states only make each poll slower, which lengthens the timeout but cannot
+/// cut a transfer short.
+pub const MAX_POLLS: u32 = 10_000;
+
+/// Bus width of a direct-mode transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Width {
+    /// SPI: SD0 out, SD1 in.
+    Single,
+    /// Two bidirectional data lines.
+    Dual,
+    /// QPI: four bidirectional data lines.
+    Quad,
+}
+
+/// The QMI operations used to identify and configure the PSRAM.
+pub trait QmiBus {
+    /// Enter direct mode, with SCK at clk_sys / `clkdiv`. With `auto_cs1n`,
+    /// CS1 is asserted around each transfer automatically.
+    fn enable_direct(&mut self, clkdiv: u8, auto_cs1n: bool);
+
+    /// Leave direct mode, releasing CS1.
+    fn disable_direct(&mut self);
+
+    /// Assert (`true`) or release CS1 by hand.
+    fn select(&mut self, asserted: bool);
+
+    /// Wait for any direct-mode transfer, or the cooldown after the last XIP
+    /// access, to finish.
+    fn wait_idle(&mut self) -> Result<(), PsramError>;
+
+    /// Clock out one byte at `width`, and return the byte clocked in.
+    fn transfer(&mut self, width: Width, data: u8) -> Result<u8, PsramError>;
+
+    /// Clock out one byte at `width`, discarding whatever is clocked in.
+    fn send(&mut self, width: Width, data: u8) -> Result<(), PsramError>;
+
+    /// Clock in one byte at `width`, with our outputs disabled so the PSRAM
+    /// can drive the bus.
+    fn receive(&mut self, width: Width) -> Result<u8, PsramError>;
+
+    /// Wait for at least `ns` nanoseconds.
+    fn delay_ns(&mut self, ns: u32);
+
+    /// Program M1 for QPI access to `part`.
+    fn configure_m1(&mut self, part: &PsramPart, timing: &PsramTiming);
+
+    /// Change only the clock-dependent fields of M1_TIMING.
+    fn update_m1_timing(&mut self, timing: &PsramTiming);
+
+    /// Map or unmap the M1 window. While it is unmapped, an access through
+    /// the translated aliases is a bus fault rather than a transfer to the
+    /// PSRAM. The untranslated alias is not affected.
+    fn map_m1(&mut self, mapped: bool);
+}
+
+/// The address translation registers covering the M1 window, 4 MiB each.
+const M1_ATRANS: core::ops::Range<usize> = 4..8;
+
+/// ATRANS size, in 4 KiB units, which maps all 4 MiB of a translation window.
+const ATRANS_FULL_SIZE: u16 = 0x400;
+
+/// Poll `$done` until it is true, or fail after [`MAX_POLLS`] attempts.
+///
+/// This is a macro rather than a function taking a closure, so the condition
+/// is always expanded into the RAM-resident caller.
+macro_rules! wait_until {
+    ($done:expr) => {{
+        let mut result = Err(PsramError::BusTimeout);
+        for _ in 0..MAX_POLLS {
+            if $done {
+                result = Ok(());
+                break;
+            }
+            nop();
+        }
+        result
+    }};
+}
+
+/// A single no-op, which unlike `rp235x_hal::arch::nop` is sure to be
+/// inlined.
+#[inline(always)]
+fn nop() {
+    // Safety: does nothing.
+    unsafe { core::arch::asm!("nop", options(nomem, nostack, preserves_flags)) };
+}
+
+impl QmiBus for QMI {
+    #[inline(always)]
+    fn enable_direct(&mut self, clkdiv: u8, auto_cs1n: bool) {
+        self.direct_csr().write(|w| unsafe {
+            w.clkdiv().bits(clkdiv);
+            w.en().set_bit();
+            w.auto_cs1n().bit(auto_cs1n);
+            w
+        });
+    }
+
+    #[inline(always)]
+    fn disable_direct(&mut self) {
+        self.direct_csr().write(|w| unsafe { w.bits(0) });
+    }
+
+    #[inline(always)]
+    fn select(&mut self, asserted: bool) {
+        self.direct_csr()
+            .modify(|_, w| w.assert_cs1n().bit(asserted));
+    }
+
+    #[inline(always)]
+    fn wait_idle(&mut self) -> Result<(), PsramError> {
+        wait_until!(self.direct_csr().read().busy().bit_is_clear())
+    }
+
+    #[inline(always)]
+    fn transfer(&mut self, width: Width, data: u8) -> Result<u8, PsramError> {
+        self.direct_tx().write(|w| unsafe {
+            set_width(w, width);
+            w.data().bits(u16::from(data));
+            w
+        });
+        wait_until!(self.direct_csr().read().txempty().bit_is_set())?;
+        self.wait_idle()?;
+        Ok(self.direct_rx().read().bits() as u8)
+    }
+
+    #[inline(always)]
+    fn send(&mut self, width: Width, data: u8) -> Result<(), PsramError> {
+        self.wait_idle()?;
+        self.direct_tx().write(|w| unsafe {
+            set_width(w, width);
+            w.nopush().set_bit();
+            w.data().bits(u16::from(data));
+            w
+        });
+        self.wait_idle()
+    }
+
+    #[inline(always)]
+    fn receive(&mut self, width: Width) -> Result<u8, PsramError> {
+        self.direct_tx().write(|w| {
+            match width {
+                Width::Single => w.iwidth().s(),
+                Width::Dual => w.iwidth().d(),
+                Width::Quad => w.iwidth().q(),
+            };
+            w
+        });
+        wait_until!(self.direct_csr().read().txempty().bit_is_set())?;
+        self.wait_idle()?;
+        Ok(self.direct_rx().read().bits() as u8)
+    }
+
+    /// We don't know clk_sys here, so this assumes it is no faster than
+    /// 500 MHz.
+    #[inline(always)]
+    fn delay_ns(&mut self, ns: u32) {
+        for _ in 0..=ns / 2 {
+            nop();
+        }
+    }
+
+    #[inline(always)]
+    fn configure_m1(&mut self, part: &PsramPart, timing: &PsramTiming) {
+        self.m1_timing().write(|w| unsafe {
+            w.cooldown().bits(1);
+            match part.page_size {
+                256 => w.pagebreak()._256(),
+                1024 => w.pagebreak()._1024(),
+                4096 => w.pagebreak()._4096(),
+                _ => w.pagebreak().none(),
+            };
+            w.max_select().bits(timing.max_select);
+            w.min_deselect().bits(timing.min_deselect);
+            w.rxdelay().bits(timing.rxdelay);
+            w.clkdiv().bits(timing.clkdiv);
+            w
+        });
+
+        // Set PSRAM commands and formats
+        self.m1_rfmt().write(|w| {
+            w.prefix_width().q();
+            w.addr_width().q();
+            w.suffix_width().q();
+            w.dummy_width().q();
+            w.data_width().q();
+            w.prefix_len()._8();
+            w.dummy_len()._24();
+            w
+        });
+
+        self.m1_rcmd()
+            .write(|w| unsafe { w.bits(u32::from(part.commands.quad_read)) });
+
+        self.m1_wfmt().write(|w| {
+            w.prefix_width().q();
+            w.addr_width().q();
+            w.suffix_width().q();
+            w.dummy_width().q();
+            w.data_width().q();
+            w.prefix_len()._8();
+            w
+        });
+
+        self.m1_wcmd()
+            .write(|w| unsafe { w.bits(u32::from(part.commands.quad_write)) });
+    }
+
+    #[inline(always)]
+    fn update_m1_timing(&mut self, timing: &PsramTiming) {
+        self.m1_timing().modify(|_, w| unsafe {
+            w.max_select().bits(timing.max_select);
+            w.min_deselect().bits(timing.min_deselect);
+            w.rxdelay().bits(timing.rxdelay);
+            w.clkdiv().bits(timing.clkdiv);
+            w
+        });
+    }
+
+    /// Only the size is changed, so any translation set up by the user is
+    /// kept, though a reduced size is restored as the full 4 MiB.
+    #[inline(always)]
+    fn map_m1(&mut self, mapped: bool) {
+        let size = if mapped { ATRANS_FULL_SIZE } else { 0 };
+        for n in M1_ATRANS {
+            self.atrans(n).modify(|_, w| unsafe { w.size().bits(size) });
+        }
+    }
+}
+
+/// Set the width of a direct-mode transfer. The output enable only matters
+/// for dual and quad; in single width SD0 is always an output.
+#[inline(always)]
+fn set_width(w: &mut rp235x_hal::pac::qmi::direct_tx::W, width: Width) {
+    match width {
+        Width::Single => {
+            w.iwidth().s();
+        }
+        Width::Dual => {
+            w.oe().set_bit();
+            w.iwidth().d();
+        }
+        Width::Quad => {
+            w.oe().set_bit();
+            w.iwidth().q();
+        }
+    }
+}
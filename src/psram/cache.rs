@@ -0,0 +1,176 @@
+//! XIP cache maintenance for PSRAM address ranges.
+//!
+//! The XIP cache is write-back, so a write through the cached PSRAM window may
+//! sit in the cache indefinitely, and a read may return a stale line. Anything
+//! else that touches the PSRAM behind the cache's back, such as DMA, direct
+//! mode, the uncached alias, or the other core after a handoff, needs the
+//! relevant lines cleaned or invalidated first.
+//!
+//! Maintenance is done by writing to the XIP maintenance window, one cache
+//! line at a time, with the operation encoded in the low address bits.
+
+use core::ops::Range;
+use core::sync::atomic::{fence, Ordering};
+
+use rp235x_hal::gpio::{PinId, PullType};
+
+use super::{Psram, PsramError, PSRAM_BASE};
+
+/// Size of an XIP cache line in bytes.
+pub const LINE_SIZE: usize = 8;
+
+/// Size of the XIP cache in bytes. Set/way operations select the set with
+/// address bits 12:3 and the way with bit 13, so this covers every line.
+const CACHE_SIZE: usize = 16 * 1024;
+
+/// The XIP address space, which holds every alias of the PSRAM.
+const XIP_SPACE: Range<usize> = 0x1000_0000..0x2000_0000;
+
+/// Base of the XIP maintenance window.
+const MAINTENANCE_BASE: usize = 0x1800_0000;
+
+/// The XIP maintenance window, which is in the XIP space but is not an alias
+/// of the PSRAM.
+const MAINTENANCE_WINDOW: Range<usize> = MAINTENANCE_BASE..0x1C00_0000;
+
+/// The address bits which give the offset into the XIP space, whichever alias
+/// an address is in.
+const XIP_OFFSET_MASK: usize = 0x03FF_FFFF;
+
+/// Maintenance operations, as encoded in the low bits of the address.
+#[derive(Clone, Copy)]
+#[repr(usize)]
+enum CacheOp {
+    InvalidateBySetWay = 0,
+    CleanBySetWay = 1,
+    InvalidateByAddress = 2,
+    CleanByAddress = 3,
+}
+
+impl<I: PinId, P: PullType> Psram<I, P> {
+    /// Write back any dirty cache lines covering `range`, so the PSRAM holds
+    /// the latest data. The lines stay in the cache.
+    ///
+    /// Do this before a DMA read of, or handing off, a buffer written through
+    /// the cached window.
+    pub fn clean_range(&self, range: Range<usize>) -> Result<(), PsramError> {
+        let offsets = self.cache_offsets(range)?;
+        maintain(offsets, &[CacheOp::CleanByAddress]);
+        Ok(())
+    }
+
+    /// Drop any cache lines covering `range` without writing them back, so
+    /// the next read fetches from the PSRAM.
+    ///
+    /// Do this after a DMA write to, or taking over, a buffer that will be
+    /// read through the cached window.
+    ///
+    /// # Safety
+    ///
+    /// Writes to the range which are still only in the cache are lost. That
+    /// includes anything else sharing the first or last cache line when
+    /// `range` is not aligned to [`LINE_SIZE`].
+    pub unsafe fn invalidate_range(&self, range: Range<usize>) -> Result<(), PsramError> {
+        let offsets = self.cache_offsets(range)?;
+        maintain(offsets, &[CacheOp::InvalidateByAddress]);
+        Ok(())
+    }
+
+    /// Write back, then drop, any cache lines covering `range`.
+    ///
+    /// This is the safe choice when the buffer is about to be both read and
+    /// written by something other than this core's cached accesses.
+    pub fn clean_invalidate_range(&self, range: Range<usize>) -> Result<(), PsramError> {
+        let offsets = self.cache_offsets(range)?;
+        maintain(
+            offsets,
+            &[CacheOp::CleanByAddress, CacheOp::InvalidateByAddress],
+        );
+        Ok(())
+    }
+
+    /// Write back every dirty line in the cache, then drop every line.
+    ///
+    /// This visits each line once, so it is much quicker than
+    /// [`Psram::clean_invalidate_range`] over the whole PSRAM. Flash lines
+    /// are dropped too, and are fetched again when next used.
+    pub fn flush_cache(&self) {
+        maintain(
+            0..CACHE_SIZE,
+            &[CacheOp::CleanBySetWay, CacheOp::InvalidateBySetWay],
+        );
+    }
+
+    fn cache_offsets(&self, range: Range<usize>) -> Result<Range<usize>, PsramError> {
+        cache_offsets(range, self.size())
+    }
+}
+
+/// Check `range` is an address range within a PSRAM of `size` bytes, in any
+/// of its aliases, and turn it into line-aligned offsets into the XIP space.
+fn cache_offsets(range: Range<usize>, size: u32) -> Result<Range<usize>, PsramError> {
+    if range.is_empty() {
+        return Ok(0..0);
+    }
+    // Only mask once we know the address is in an alias of the PSRAM, or
+    // anything else which happens to share the offset bits would match.
+    if !XIP_SPACE.contains(&range.start) || MAINTENANCE_WINDOW.contains(&range.start) {
+        return Err(PsramError::OutOfBounds);
+    }
+    let start = range.start & XIP_OFFSET_MASK;
+    let len = range.end - range.start;
+    let base = PSRAM_BASE & XIP_OFFSET_MASK;
+    if start < base || start + len > base + size as usize {
+        return Err(PsramError::OutOfBounds);
+    }
+    Ok((start & !(LINE_SIZE - 1))..(start + len))
+}
+
+/// Apply `ops` to each cache line in `offsets`, in order.
+fn maintain(offsets: Range<usize>, ops: &[CacheOp]) {
+    // Make sure our own earlier writes have reached the cache.
+    fence(Ordering::SeqCst);
+    for line in offsets.step_by(LINE_SIZE) {
+        for &op in ops {
+            let ptr = (MAINTENANCE_BASE + line + op as usize) as *mut u8;
+            // Safety: writes to the maintenance window only act on the cache
+            // and do not touch memory.
+            unsafe { ptr.write_volatile(0) };
+        }
+    }
+    fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 8 * 1024 * 1024;
+
+    #[test]
+    fn offsets_in_every_alias() {
+        for base in [0x1100_0000, 0x1500_0000, 0x1D00_0000] {
+            assert_eq!(
+                cache_offsets(base + 0x13..base + 0x40, SIZE),
+                Ok(0x0100_0010..0x0100_0040)
+            );
+        }
+        assert_eq!(
+            cache_offsets(0x1100_0000..0x1180_0000, SIZE),
+            Ok(0x0100_0000..0x0180_0000)
+        );
+    }
+
+    #[test]
+    fn rejects_addresses_outside_the_psram() {
+        // SRAM and peripherals share offset bits with the PSRAM.
+        assert!(cache_offsets(0x2100_0000..0x2100_0040, SIZE).is_err());
+        assert!(cache_offsets(0x5100_0000..0x5100_0040, SIZE).is_err());
+        // The maintenance window is in the XIP space but is not an alias.
+        assert!(cache_offsets(0x1900_0000..0x1900_0040, SIZE).is_err());
+        // Flash, and past the end of the PSRAM.
+        assert!(cache_offsets(0x1000_0000..0x1000_0040, SIZE).is_err());
+        assert!(cache_offsets(0x117F_FFF0..0x1180_0010, SIZE).is_err());
+        assert_eq!(cache_offsets(0x2100_0000..0x2100_0000, SIZE), Ok(0..0));
+    }
+}
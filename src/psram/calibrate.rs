@@ -0,0 +1,363 @@
+//! Sweep the M1 read timing to find the most robust setting for a board.
+//!
+//! The rxdelay picked by [`PsramTiming::compute`] is a heuristic tuned for
+//! the APS6404 on one board layout. [`Psram::calibrate`] instead tries every
+//! rxdelay at a few clock divisors, runs a pattern test through the M1 window
+//! for each, and picks the centre of the widest passing window (the "eye") at
+//! the fastest divisor that has one.
+
+use core::ops::Range;
+
+use rp235x_hal::clocks::{Clock, SystemClock};
+use rp235x_hal::gpio::{PinId, PullType};
+
+use super::{write_m1_timing, Psram, PsramError, PsramTiming, PSRAM_NOCACHE_BASE};
+
+/// How many clock divisors to try, starting from the fastest the part allows.
+const CLKDIV_STEPS: u32 = 3;
+
+/// The narrowest eye, in rxdelay steps, we are prepared to run in.
+const MIN_EYE_WIDTH: u8 = 2;
+
+/// The number of rxdelay settings (`M1_TIMING.RXDELAY` is 3 bits).
+const RXDELAY_STEPS: u8 = 8;
+
+/// Starting value for the check byte of a stored [`Calibration`], so that
+/// all zeros does not check out.
+const CHECK_SEED: u8 = 0xA5;
+
+/// The result of a calibration sweep.
+///
+/// This is only valid for the clk_sys frequency it was measured at. It can be
+/// stored with [`Calibration::to_bytes`] and applied on subsequent boots with
+/// [`Psram::apply_calibration`] to skip the sweep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Calibration {
+    /// The clk_sys frequency the sweep ran at.
+    pub sys_clock_hz: u32,
+    /// The chosen clock divisor.
+    pub clkdiv: u8,
+    /// The chosen rxdelay, the centre of the eye.
+    pub rxdelay: u8,
+    /// The first passing rxdelay at `clkdiv`.
+    pub eye_start: u8,
+    /// The last passing rxdelay at `clkdiv`.
+    pub eye_end: u8,
+}
+
+impl Calibration {
+    /// Encode for storage.
+    ///
+    /// The eye bounds share a byte, each being 3 bits, and the last byte is a
+    /// check over the rest.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let clock = self.sys_clock_hz.to_le_bytes();
+        let mut bytes = [
+            clock[0],
+            clock[1],
+            clock[2],
+            clock[3],
+            self.clkdiv,
+            self.rxdelay,
+            self.eye_start | self.eye_end << 4,
+            0,
+        ];
+        bytes[7] = check(&bytes[..7]);
+        bytes
+    }
+
+    /// Decode from storage, returning `None` if the bytes do not describe a
+    /// plausible calibration (such as erased flash), or fail the check.
+    pub fn from_bytes(bytes: [u8; 8]) -> Option<Self> {
+        if bytes[7] != check(&bytes[..7]) {
+            return None;
+        }
+        let calibration = Calibration {
+            sys_clock_hz: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            clkdiv: bytes[4],
+            rxdelay: bytes[5],
+            eye_start: bytes[6] & 0xF,
+            eye_end: bytes[6] >> 4,
+        };
+        let plausible = calibration.sys_clock_hz != 0
+            && calibration.sys_clock_hz != u32::MAX
+            && calibration.clkdiv != 0
+            && calibration.eye_start <= calibration.rxdelay
+            && calibration.rxdelay <= calibration.eye_end
+            && calibration.eye_end < RXDELAY_STEPS;
+        plausible.then_some(calibration)
+    }
+}
+
+/// The check byte stored after an encoded [`Calibration`].
+fn check(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(CHECK_SEED, |sum, &byte| {
+        sum.rotate_left(1).wrapping_add(byte)
+    })
+}
+
+/// Find the widest run of passing rxdelays.
+///
+/// Bit `n` of `passes` is set if rxdelay `n` passed. Returns the first and
+/// last rxdelay of the run; ties go to the lower run.
+pub fn find_eye(passes: u8) -> Option<(u8, u8)> {
+    let mut best: Option<(u8, u8)> = None;
+    let mut start = None;
+    for rxdelay in 0..=RXDELAY_STEPS {
+        let passed = rxdelay < RXDELAY_STEPS && passes & (1 << rxdelay) != 0;
+        match (passed, start) {
+            (true, None) => start = Some(rxdelay),
+            (false, Some(first)) => {
+                let last = rxdelay - 1;
+                if best.map_or(true, |(s, e)| last - first > e - s) {
+                    best = Some((first, last));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    best
+}
+
+impl<I: PinId, P: PullType> Psram<I, P> {
+    /// Sweep clkdiv and rxdelay, pattern testing `scratch` at each setting,
+    /// and program the best timing found.
+    ///
+    /// `scratch` is a byte range within the PSRAM whose contents will be
+    /// destroyed. It must be word aligned; a few KiB is plenty. The previous
+    /// timing is restored if no setting passes.
+    ///
+    /// Most settings tried will return corrupt data, so nothing else may use
+    /// the PSRAM while this runs. If a lockout is set, the other core is
+    /// parked for the whole sweep.
+    pub fn calibrate(&mut self, scratch: Range<u32>) -> Result<Calibration, PsramError> {
+        self.check_awake()?;
+        self.parked(|psram| psram.sweep(scratch))
+    }
+
+    fn sweep(&mut self, scratch: Range<u32>) -> Result<Calibration, PsramError> {
+        let words = self.scratch_words(&scratch)?;
+        self.search(words).or_else(|err| {
+            // Put back the timing `self.info` describes, rather than leaving
+            // M1 at whichever setting was being tried.
+            write_m1_timing(&mut self.qmi, &self.info.timing)?;
+            Err(err)
+        })
+    }
+
+    /// The sweep itself. This leaves M1 at whatever it was trying if it
+    /// fails, and only updates `self.info` once it has succeeded.
+    fn search(&mut self, words: Range<*mut u32>) -> Result<Calibration, PsramError> {
+        let params = self.info.part.params;
+        let default = PsramTiming::compute(self.clock_hz, &params)?;
+
+        let mut chosen: Option<(PsramTiming, u8, u8)> = None;
+        for clkdiv in u32::from(default.clkdiv)..u32::from(default.clkdiv) + CLKDIV_STEPS {
+            let mut passes = 0u8;
+            for rxdelay in 0..RXDELAY_STEPS {
+                let Ok(timing) =
+                    PsramTiming::with_divisor(self.clock_hz, &params, clkdiv, u32::from(rxdelay))
+                else {
+                    continue;
+                };
+                write_m1_timing(&mut self.qmi, &timing)?;
+                if pattern_test(words.clone()) {
+                    passes |= 1 << rxdelay;
+                }
+            }
+            let Some((start, end)) = find_eye(passes) else {
+                continue;
+            };
+            let centre = start + (end - start) / 2;
+            let timing =
+                PsramTiming::with_divisor(self.clock_hz, &params, clkdiv, u32::from(centre))?;
+            let wide_enough = end - start + 1 >= MIN_EYE_WIDTH;
+            // Take the fastest divisor with a wide enough eye, otherwise
+            // remember the first eye we saw as a last resort.
+            if wide_enough || chosen.is_none() {
+                chosen = Some((timing, start, end));
+            }
+            if wide_enough {
+                break;
+            }
+        }
+
+        let (timing, eye_start, eye_end) = chosen.ok_or(PsramError::CalibrationFailed)?;
+        write_m1_timing(&mut self.qmi, &timing)?;
+        self.info.timing = timing;
+
+        Ok(Calibration {
+            sys_clock_hz: self.clock_hz,
+            clkdiv: timing.clkdiv,
+            rxdelay: timing.rxdelay,
+            eye_start,
+            eye_end,
+        })
+    }
+
+    /// Program the timing from an earlier [`Psram::calibrate`].
+    ///
+    /// Fails with [`PsramError::ClockOutOfRange`] if clk_sys is not running at
+    /// the frequency the calibration was measured at.
+    pub fn apply_calibration(
+        &mut self,
+        system_clock: &SystemClock,
+        calibration: &Calibration,
+    ) -> Result<PsramTiming, PsramError> {
+        let sys_clock_hz = system_clock.freq().to_Hz();
+        if calibration.sys_clock_hz != sys_clock_hz {
+            return Err(PsramError::ClockOutOfRange {
+                clock_hz: calibration.sys_clock_hz,
+            });
+        }
+        let timing = PsramTiming::with_divisor(
+            sys_clock_hz,
+            &self.info.part.params,
+            u32::from(calibration.clkdiv),
+            u32::from(calibration.rxdelay),
+        )?;
+        self.parked(|psram| write_m1_timing(&mut psram.qmi, &timing))?;
+        self.info.timing = timing;
+        self.clock_hz = sys_clock_hz;
+        Ok(timing)
+    }
+
+    /// Check `scratch` lies within the PSRAM, and turn it into a range of word
+    /// pointers in the uncached alias.
+    fn scratch_words(&self, scratch: &Range<u32>) -> Result<Range<*mut u32>, PsramError> {
+        if scratch.is_empty() || scratch.end > self.info.size() {
+            return Err(PsramError::OutOfBounds);
+        }
+        if scratch.start % 4 != 0 || scratch.end % 4 != 0 {
+            return Err(PsramError::NotAligned);
+        }
+        let start = (PSRAM_NOCACHE_BASE + scratch.start as usize) as *mut u32;
+        let end = (PSRAM_NOCACHE_BASE + scratch.end as usize) as *mut u32;
+        Ok(start..end)
+    }
+}
+
+/// Write address-dependent patterns over `words` and check they read back.
+///
+/// Going through the uncached alias means every read really goes out to the
+/// PSRAM with the timing under test.
+fn pattern_test(words: Range<*mut u32>) -> bool {
+    for invert in [0, u32::MAX] {
+        let mut ptr = words.start;
+        while ptr < words.end {
+            // Safety: `scratch_words` checked the range is within the PSRAM,
+            // and the caller gave it to us to clobber.
+            unsafe { ptr.write_volatile(pattern(ptr) ^ invert) };
+            ptr = ptr.wrapping_add(1);
+        }
+        let mut ptr = words.start;
+        while ptr < words.end {
+            // Safety: as above.
+            if unsafe { ptr.read_volatile() } != pattern(ptr) ^ invert {
+                return false;
+            }
+            ptr = ptr.wrapping_add(1);
+        }
+    }
+    true
+}
+
+/// A pattern which differs in every byte lane from one word to the next, so
+/// both data and address faults show up.
+fn pattern(ptr: *mut u32) -> u32 {
+    (ptr as u32).wrapping_mul(0x9E37_79B9) ^ 0x5A5A_A5A5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_eye() {
+        assert_eq!(find_eye(0), None);
+    }
+
+    #[test]
+    fn eye_runs() {
+        assert_eq!(find_eye(0xFF), Some((0, 7)));
+        assert_eq!(find_eye(0b0001_0000), Some((4, 4)));
+        assert_eq!(find_eye(0b0000_0111), Some((0, 2)));
+        assert_eq!(find_eye(0b1110_0000), Some((5, 7)));
+        assert_eq!(find_eye(0b1000_0011), Some((0, 1)));
+        assert_eq!(find_eye(0b1110_0001), Some((5, 7)));
+        assert_eq!(find_eye(0b0111_1010), Some((3, 6)));
+    }
+
+    #[test]
+    fn eye_ties_go_to_the_lower_run() {
+        assert_eq!(find_eye(0b0110_0110), Some((1, 2)));
+        assert_eq!(find_eye(0b1000_0001), Some((0, 0)));
+        assert_eq!(find_eye(0b1100_0011), Some((0, 1)));
+    }
+
+    const CALIBRATION: Calibration = Calibration {
+        sys_clock_hz: 150_000_000,
+        clkdiv: 2,
+        rxdelay: 3,
+        eye_start: 1,
+        eye_end: 6,
+    };
+
+    #[test]
+    fn bytes_round_trip() {
+        assert_eq!(
+            Calibration::from_bytes(CALIBRATION.to_bytes()),
+            Some(CALIBRATION)
+        );
+        let edge = Calibration {
+            rxdelay: 7,
+            eye_start: 7,
+            eye_end: 7,
+            ..CALIBRATION
+        };
+        assert_eq!(Calibration::from_bytes(edge.to_bytes()), Some(edge));
+    }
+
+    #[test]
+    fn erased_bytes_are_rejected() {
+        assert_eq!(Calibration::from_bytes([0xFF; 8]), None);
+        assert_eq!(Calibration::from_bytes([0x00; 8]), None);
+    }
+
+    #[test]
+    fn bad_check_is_rejected() {
+        let good = CALIBRATION.to_bytes();
+        for i in 0..8 {
+            let mut bytes = good;
+            bytes[i] ^= 0x01;
+            assert_eq!(Calibration::from_bytes(bytes), None, "byte {i}");
+        }
+    }
+
+    #[test]
+    fn implausible_values_are_rejected() {
+        for calibration in [
+            Calibration {
+                clkdiv: 0,
+                ..CALIBRATION
+            },
+            Calibration {
+                rxdelay: 0,
+                ..CALIBRATION
+            },
+            Calibration {
+                rxdelay: 7,
+                ..CALIBRATION
+            },
+            Calibration {
+                eye_end: 8,
+                rxdelay: 8,
+                ..CALIBRATION
+            },
+        ] {
+            // The check is over the encoded bytes, so these still pass it.
+            assert_eq!(Calibration::from_bytes(calibration.to_bytes()), None);
+        }
+    }
+}
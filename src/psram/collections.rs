@@ -0,0 +1,436 @@
+//! Containers that allocate from a [`PsramHeap`] rather than the global
+//! allocator.
+//!
+//! These are for the few large buffers that belong in PSRAM, leaving the
+//! global allocator (if any) in fast SRAM. They work on stable Rust, so
+//! instead of an `Allocator` parameter each one keeps a reference to its heap,
+//! and allocation failures are returned as [`PsramError::OutOfMemory`]
+//! instead of aborting.
+//!
+//! ```ignore
+//! static PSRAM_HEAP: PsramHeap = PsramHeap::empty();
+//!
+//! unsafe { PSRAM_HEAP.init(psram::PSRAM_BASE, psram.size() as usize) };
+//! let frame = PsramBox::new(&PSRAM_HEAP, [0u16; 320 * 240])?;
+//! let mut log = PsramString::new(&PSRAM_HEAP);
+//! log.push_str("boot\n")?;
+//! ```
+
+use core::alloc::Layout;
+use core::fmt;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::slice;
+use core::str;
+
+use super::{PsramError, PsramHeap};
+
+/// A `Box` in PSRAM.
+pub struct PsramBox<T> {
+    ptr: NonNull<T>,
+    heap: &'static PsramHeap,
+}
+
+// Safety: a PsramBox owns its T just like a Box does, and the heap is Sync.
+unsafe impl<T: Send> Send for PsramBox<T> {}
+unsafe impl<T: Sync> Sync for PsramBox<T> {}
+
+impl<T> PsramBox<T> {
+    /// Move `value` into PSRAM.
+    ///
+    /// Note that `value` is built on the stack first, so a very large array
+    /// is better made as a [`PsramVec`].
+    pub fn new(heap: &'static PsramHeap, value: T) -> Result<Self, PsramError> {
+        let layout = Layout::new::<T>();
+        let ptr = if layout.size() == 0 {
+            NonNull::dangling()
+        } else {
+            heap.allocate(layout)?.cast()
+        };
+        // Safety: ptr is valid and aligned for a T.
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(PsramBox { ptr, heap })
+    }
+
+    /// Move the value back out of PSRAM, freeing its memory.
+    pub fn into_inner(this: Self) -> T {
+        let this = mem::ManuallyDrop::new(this);
+        // Safety: the value is read exactly once, and the drop that would
+        // read it again is suppressed.
+        unsafe {
+            let value = this.ptr.as_ptr().read();
+            free(this.heap, this.ptr, Layout::new::<T>());
+            value
+        }
+    }
+}
+
+impl<T> Drop for PsramBox<T> {
+    fn drop(&mut self) {
+        // Safety: the value is live and owned by us.
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            free(self.heap, self.ptr, Layout::new::<T>());
+        }
+    }
+}
+
+impl<T> Deref for PsramBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: the value is live, and borrowed through self.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for PsramBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: the value is live, and borrowed through self.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PsramBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for PsramBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: defmt::Format> defmt::Format for PsramBox<T> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::Format::format(&**self, f)
+    }
+}
+
+/// A `Vec` in PSRAM.
+///
+/// Growing it may fail, so [`PsramVec::push`] and friends return a `Result`.
+/// Capacity doubles on growth, as with `Vec`; use
+/// [`PsramVec::with_capacity`] when the final size is known.
+pub struct PsramVec<T> {
+    ptr: NonNull<T>,
+    len: usize,
+    capacity: usize,
+    heap: &'static PsramHeap,
+}
+
+// Safety: a PsramVec owns its elements just like a Vec does.
+unsafe impl<T: Send> Send for PsramVec<T> {}
+unsafe impl<T: Sync> Sync for PsramVec<T> {}
+
+impl<T> PsramVec<T> {
+    /// An empty vector, which does not allocate until something is pushed.
+    pub const fn new(heap: &'static PsramHeap) -> Self {
+        PsramVec {
+            ptr: NonNull::dangling(),
+            len: 0,
+            capacity: if mem::size_of::<T>() == 0 {
+                usize::MAX
+            } else {
+                0
+            },
+            heap,
+        }
+    }
+
+    /// An empty vector with room for at least `capacity` elements.
+    pub fn with_capacity(heap: &'static PsramHeap, capacity: usize) -> Result<Self, PsramError> {
+        let mut vec = Self::new(heap);
+        vec.reserve_exact(capacity)?;
+        Ok(vec)
+    }
+
+    /// The number of elements.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of elements that fit without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Make room for at least `additional` more elements, growing by at
+    /// least double to keep repeated pushes cheap.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), PsramError> {
+        let needed = self
+            .len
+            .checked_add(additional)
+            .ok_or(PsramError::OutOfMemory)?;
+        if needed <= self.capacity {
+            return Ok(());
+        }
+        self.grow(needed.max(self.capacity * 2).max(4))
+    }
+
+    /// Make room for exactly `additional` more elements.
+    pub fn reserve_exact(&mut self, additional: usize) -> Result<(), PsramError> {
+        let needed = self
+            .len
+            .checked_add(additional)
+            .ok_or(PsramError::OutOfMemory)?;
+        if needed <= self.capacity {
+            return Ok(());
+        }
+        self.grow(needed)
+    }
+
+    /// Add `value` to the end. If there is no room it is handed back.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == self.capacity && self.reserve(1).is_err() {
+            return Err(value);
+        }
+        // Safety: len < capacity, so the slot is allocated and unused.
+        unsafe { self.ptr.as_ptr().add(self.len).write(value) };
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove the last element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        // Safety: the slot was initialised, and is now past len.
+        Some(unsafe { self.ptr.as_ptr().add(self.len).read() })
+    }
+
+    /// Drop the elements past `len`.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        let tail = ptr::slice_from_raw_parts_mut(
+            // Safety: len < self.len, so this is inside the allocation.
+            unsafe { self.ptr.as_ptr().add(len) },
+            self.len - len,
+        );
+        // Shorten first so a panicking drop cannot cause a double drop.
+        self.len = len;
+        // Safety: the tail was initialised, and is now past len.
+        unsafe { ptr::drop_in_place(tail) };
+    }
+
+    /// Drop every element, keeping the memory.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Append clones of everything in `items`. On failure nothing is added.
+    pub fn extend_from_slice(&mut self, items: &[T]) -> Result<(), PsramError>
+    where
+        T: Clone,
+    {
+        self.reserve(items.len())?;
+        for item in items {
+            // Safety: reserve made room for all of items.
+            unsafe { self.ptr.as_ptr().add(self.len).write(item.clone()) };
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    /// Shorten to `len`, or lengthen with clones of `value`. On failure
+    /// nothing is added.
+    pub fn resize(&mut self, len: usize, value: T) -> Result<(), PsramError>
+    where
+        T: Clone,
+    {
+        if len <= self.len {
+            self.truncate(len);
+            return Ok(());
+        }
+        self.reserve_exact(len - self.len)?;
+        while self.len < len {
+            // Safety: reserve_exact made room up to len.
+            unsafe { self.ptr.as_ptr().add(self.len).write(value.clone()) };
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    /// Move the allocation to one with room for `capacity` elements.
+    fn grow(&mut self, capacity: usize) -> Result<(), PsramError> {
+        // Zero-sized types start at usize::MAX and never get here.
+        let layout = Layout::array::<T>(capacity).map_err(|_| PsramError::OutOfMemory)?;
+        let ptr = if self.capacity == 0 {
+            self.heap.allocate(layout)?
+        } else {
+            // Safety: the old layout is what it was allocated with.
+            unsafe {
+                self.heap
+                    .reallocate(self.ptr.cast(), self.layout(), layout.size())?
+            }
+        };
+        self.ptr = ptr.cast();
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    /// The layout of the current allocation.
+    fn layout(&self) -> Layout {
+        // Safety: this layout was checked when it was allocated.
+        unsafe {
+            Layout::from_size_align_unchecked(
+                mem::size_of::<T>() * self.capacity,
+                mem::align_of::<T>(),
+            )
+        }
+    }
+}
+
+impl<T> Drop for PsramVec<T> {
+    fn drop(&mut self) {
+        self.clear();
+        if mem::size_of::<T>() != 0 && self.capacity != 0 {
+            // Safety: the memory came from this heap with this layout.
+            unsafe { self.heap.deallocate(self.ptr.cast(), self.layout()) };
+        }
+    }
+}
+
+impl<T> Deref for PsramVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // Safety: the first len elements are initialised.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for PsramVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // Safety: the first len elements are initialised.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PsramVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: defmt::Format> defmt::Format for PsramVec<T> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::Format::format(&**self, f)
+    }
+}
+
+/// A `String` in PSRAM.
+///
+/// It implements [`fmt::Write`], so `write!` works on it, with running out
+/// of memory reported as [`fmt::Error`].
+pub struct PsramString {
+    bytes: PsramVec<u8>,
+}
+
+impl PsramString {
+    /// An empty string, which does not allocate until something is pushed.
+    pub const fn new(heap: &'static PsramHeap) -> Self {
+        PsramString {
+            bytes: PsramVec::new(heap),
+        }
+    }
+
+    /// An empty string with room for at least `capacity` bytes.
+    pub fn with_capacity(heap: &'static PsramHeap, capacity: usize) -> Result<Self, PsramError> {
+        Ok(PsramString {
+            bytes: PsramVec::with_capacity(heap, capacity)?,
+        })
+    }
+
+    /// The string's contents.
+    pub fn as_str(&self) -> &str {
+        // Safety: only whole strs and chars are ever added.
+        unsafe { str::from_utf8_unchecked(&self.bytes) }
+    }
+
+    /// The length in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Whether the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// The number of bytes that fit without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.bytes.capacity()
+    }
+
+    /// Append `s`. On failure nothing is added.
+    pub fn push_str(&mut self, s: &str) -> Result<(), PsramError> {
+        self.bytes.extend_from_slice(s.as_bytes())
+    }
+
+    /// Append `c`. On failure nothing is added.
+    pub fn push(&mut self, c: char) -> Result<(), PsramError> {
+        self.push_str(c.encode_utf8(&mut [0; 4]))
+    }
+
+    /// Empty the string, keeping the memory.
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+impl Deref for PsramString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Write for PsramString {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Debug for PsramString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for PsramString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl defmt::Format for PsramString {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::Format::format(self.as_str(), f)
+    }
+}
+
+/// Free the memory behind a [`PsramBox`], if it had any.
+///
+/// # Safety
+///
+/// `ptr` must have come from `heap` with `layout`, or be dangling for a
+/// zero-sized `layout`.
+unsafe fn free<T>(heap: &PsramHeap, ptr: NonNull<T>, layout: Layout) {
+    if layout.size() != 0 {
+        heap.deallocate(ptr.cast(), layout);
+    }
+}
@@ -0,0 +1,164 @@
+//! A file-like cursor over a PSRAM region, for `embedded-io` users.
+
+use embedded_io::{ErrorKind, ErrorType, Read, Seek, SeekFrom, Write};
+
+use super::{PsramError, PsramRegion};
+
+/// Reads and writes a [`PsramRegion`] sequentially, like a file of fixed
+/// size.
+///
+/// Reads at the end of the region return 0 bytes. Writes there, and seeks
+/// outside the region, fail with [`PsramError::OutOfBounds`].
+#[derive(Debug, defmt::Format)]
+pub struct PsramCursor<'p> {
+    region: PsramRegion<'p>,
+    pos: usize,
+}
+
+impl<'p> PsramCursor<'p> {
+    /// A cursor at the start of `region`.
+    pub fn new(region: PsramRegion<'p>) -> Self {
+        PsramCursor { region, pos: 0 }
+    }
+
+    /// Offset of the cursor from the start of the region.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Give the region back.
+    pub fn free(self) -> PsramRegion<'p> {
+        self.region
+    }
+}
+
+impl embedded_io::Error for PsramError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            PsramError::OutOfBounds | PsramError::NotAligned => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorType for PsramCursor<'_> {
+    type Error = PsramError;
+}
+
+impl Read for PsramCursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, PsramError> {
+        let remaining = &self.region.as_slice()[self.pos..];
+        let len = buf.len().min(remaining.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Write for PsramCursor<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, PsramError> {
+        let pos = self.pos;
+        let remaining = &mut self.region.as_mut_slice()[pos..];
+        if remaining.is_empty() && !buf.is_empty() {
+            return Err(PsramError::OutOfBounds);
+        }
+        let len = buf.len().min(remaining.len());
+        remaining[..len].copy_from_slice(&buf[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), PsramError> {
+        Ok(())
+    }
+}
+
+impl Seek for PsramCursor<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, PsramError> {
+        self.pos = seek(self.pos, self.region.len(), pos)?;
+        Ok(self.pos as u64)
+    }
+}
+
+/// Where a seek from `pos` ends up, in something `len` bytes long.
+fn seek(pos: usize, len: usize, to: SeekFrom) -> Result<usize, PsramError> {
+    let (base, delta) = match to {
+        SeekFrom::Start(offset) => (0, i64::try_from(offset).ok()),
+        SeekFrom::End(delta) => (len, Some(delta)),
+        SeekFrom::Current(delta) => (pos, Some(delta)),
+    };
+    delta
+        .and_then(|delta| (base as i64).checked_add(delta))
+        .and_then(|pos| usize::try_from(pos).ok())
+        .filter(|&pos| pos <= len)
+        .ok_or(PsramError::OutOfBounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A region can only be backed by the PSRAM itself, so these test the
+    // arithmetic on its own.
+
+    const LEN: usize = 100;
+
+    #[test]
+    fn seek_from_start() {
+        assert_eq!(seek(50, LEN, SeekFrom::Start(0)), Ok(0));
+        assert_eq!(seek(50, LEN, SeekFrom::Start(100)), Ok(100));
+        assert_eq!(
+            seek(50, LEN, SeekFrom::Start(101)),
+            Err(PsramError::OutOfBounds)
+        );
+        assert_eq!(
+            seek(50, LEN, SeekFrom::Start(u64::MAX)),
+            Err(PsramError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn seek_from_end() {
+        assert_eq!(seek(0, LEN, SeekFrom::End(0)), Ok(100));
+        assert_eq!(seek(0, LEN, SeekFrom::End(-1)), Ok(99));
+        assert_eq!(seek(0, LEN, SeekFrom::End(-100)), Ok(0));
+        assert_eq!(
+            seek(0, LEN, SeekFrom::End(-101)),
+            Err(PsramError::OutOfBounds)
+        );
+        assert_eq!(seek(0, LEN, SeekFrom::End(1)), Err(PsramError::OutOfBounds));
+    }
+
+    #[test]
+    fn seek_from_current() {
+        assert_eq!(seek(50, LEN, SeekFrom::Current(0)), Ok(50));
+        assert_eq!(seek(50, LEN, SeekFrom::Current(-20)), Ok(30));
+        assert_eq!(seek(50, LEN, SeekFrom::Current(-50)), Ok(0));
+        assert_eq!(seek(50, LEN, SeekFrom::Current(50)), Ok(100));
+        assert_eq!(
+            seek(50, LEN, SeekFrom::Current(-51)),
+            Err(PsramError::OutOfBounds)
+        );
+        assert_eq!(
+            seek(50, LEN, SeekFrom::Current(51)),
+            Err(PsramError::OutOfBounds)
+        );
+        assert_eq!(
+            seek(50, LEN, SeekFrom::Current(i64::MAX)),
+            Err(PsramError::OutOfBounds)
+        );
+        assert_eq!(
+            seek(50, LEN, SeekFrom::Current(i64::MIN)),
+            Err(PsramError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(seek(0, 0, SeekFrom::End(0)), Ok(0));
+        assert_eq!(
+            seek(0, 0, SeekFrom::Current(1)),
+            Err(PsramError::OutOfBounds)
+        );
+    }
+}
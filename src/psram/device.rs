@@ -0,0 +1,376 @@
+//! PSRAM identification and the table of parts we know how to drive.
+
+use super::{ChipParams, PsramError};
+
+/// The identification bytes returned by the PSRAM Read ID (0x9F) command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PsramId {
+    /// The raw bytes following the command and address phase: MF ID, KGD and
+    /// the first six bytes of the EID.
+    pub raw: [u8; 8],
+}
+
+impl PsramId {
+    /// Manufacturer ID byte.
+    pub fn manufacturer(&self) -> u8 {
+        self.raw[0]
+    }
+
+    /// Known Good Die byte. AP Memory parts report 0x5D here.
+    pub fn kgd(&self) -> u8 {
+        self.raw[1]
+    }
+
+    /// First byte of the extended ID.
+    pub fn eid(&self) -> u8 {
+        self.raw[2]
+    }
+
+    /// Density bits, taken from the top three bits of the EID.
+    pub fn density_bits(&self) -> u8 {
+        self.eid() >> 5
+    }
+
+    /// Decode the ID using the [`PARTS`] table.
+    pub fn device(&self) -> PsramDevice {
+        if let Some(part) = PARTS.iter().find(|part| part.matches(self)) {
+            return PsramDevice::Known(part);
+        }
+        // A floating or missing chip reads back as all ones or all zeros.
+        let floating =
+            self.raw[..3].iter().all(|&b| b == 0xFF) || self.raw[..3].iter().all(|&b| b == 0x00);
+        if floating {
+            PsramDevice::NotPresent
+        } else {
+            PsramDevice::Unrecognised(*self)
+        }
+    }
+
+    /// Size of the detected device in bytes, or `None` if it was not
+    /// recognised.
+    pub fn size(&self) -> Option<u32> {
+        self.device().size()
+    }
+}
+
+/// A PSRAM device decoded from its [`PsramId`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PsramDevice {
+    /// A part from the [`PARTS`] table.
+    Known(&'static PsramPart),
+    /// Something answered Read ID, but it is not in the [`PARTS`] table.
+    Unrecognised(PsramId),
+    /// Nothing answered Read ID.
+    NotPresent,
+}
+
+impl PsramDevice {
+    /// The table entry for this device, if it is known.
+    pub fn part(&self) -> Option<&'static PsramPart> {
+        match self {
+            PsramDevice::Known(part) => Some(part),
+            _ => None,
+        }
+    }
+
+    /// Size of the device in bytes, or `None` if it is not known.
+    pub fn size(&self) -> Option<u32> {
+        self.part().map(|part| part.size)
+    }
+
+    /// The table entry for this device, or the error reported when bringing
+    /// it up.
+    pub fn known(self) -> Result<&'static PsramPart, PsramError> {
+        match self {
+            PsramDevice::Known(part) => Ok(part),
+            PsramDevice::Unrecognised(id) => Err(PsramError::UnsupportedDevice(id)),
+            PsramDevice::NotPresent => Err(PsramError::NotDetected),
+        }
+    }
+}
+
+/// Whose IDs a part reports, which is not always who made it.
+///
+/// Second-source parts such as the Lyontek LY68L6400 and Espressif
+/// ESP-PSRAM64H report AP Memory's manufacturer ID, so are indistinguishable
+/// from the AP Memory original and share its table entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum IdFamily {
+    ApMemory,
+    Issi,
+}
+
+/// The commands used to bring up and access a part over QPI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct CommandSet {
+    /// Switch from SPI to QPI mode (sent as SPI).
+    pub enter_qpi: u8,
+    /// Switch from QPI back to SPI mode (sent as QPI).
+    pub exit_qpi: u8,
+    /// Read ID (sent as SPI).
+    pub read_id: u8,
+    /// Quad fast read, with 6 wait cycles.
+    pub quad_read: u8,
+    /// Quad write.
+    pub quad_write: u8,
+    /// Reset Enable, which must immediately precede `reset`.
+    pub reset_enable: u8,
+    /// Reset, returning the part to SPI mode.
+    pub reset: u8,
+}
+
+/// The command set shared by the AP Memory APS*04 family and its clones.
+pub const APS_COMMANDS: CommandSet = CommandSet {
+    enter_qpi: 0x35,
+    exit_qpi: 0xF5,
+    read_id: 0x9F,
+    quad_read: 0xEB,
+    quad_write: 0x38,
+    reset_enable: 0x66,
+    reset: 0x99,
+};
+
+/// The kinds of low-power state a part may offer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SleepKind {
+    /// Self-refresh continues, so the contents are kept.
+    HalfSleep,
+    /// Everything is powered down, and the contents are lost.
+    DeepPowerDown,
+}
+
+/// A part's low-power state, and how to enter and leave it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct SleepMode {
+    pub kind: SleepKind,
+    /// Command which enters the state (sent as QPI).
+    pub command: u8,
+    /// How long after the command before the part is asleep (tHS).
+    pub enter_ns: u32,
+    /// How long CS must be held low to start waking up (tXPHS).
+    pub wake_pulse_ns: u32,
+    /// How long after that before the part may be accessed (tXHS).
+    pub wake_ns: u32,
+}
+
+impl SleepMode {
+    /// Are the contents kept while asleep?
+    pub fn retains_data(&self) -> bool {
+        self.kind == SleepKind::HalfSleep
+    }
+}
+
+/// Half Sleep on the AP Memory APS*04 family.
+pub const APS_HALF_SLEEP: SleepMode = SleepMode {
+    kind: SleepKind::HalfSleep,
+    command: 0xC0,
+    enter_ns: 150,
+    wake_pulse_ns: 60,
+    wake_ns: 150_000,
+};
+
+/// Timing limits of the AP Memory APS*04 family.
+pub const APS_PARAMS: ChipParams = ChipParams {
+    max_clock_hz: 133_000_000,
+    tcem_ns: 8_000,
+    min_deselect_ns: 18,
+};
+
+/// A QSPI PSRAM part and the parameters needed to configure QMI for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PsramPart {
+    /// Whose IDs the part reports.
+    pub family: IdFamily,
+    /// Part number(s). Second-source parts which report identical IDs share
+    /// an entry.
+    pub name: &'static str,
+    /// Expected manufacturer ID byte.
+    pub manufacturer: u8,
+    /// Expected KGD byte.
+    pub kgd: u8,
+    /// Bits of the EID compared against `eid`.
+    pub eid_mask: u8,
+    /// Expected EID value after masking.
+    pub eid: u8,
+    /// Size in bytes.
+    pub size: u32,
+    /// Page size in bytes; bursts must not cross a page boundary.
+    pub page_size: u32,
+    /// Timing limits used to configure QMI.
+    pub params: ChipParams,
+    pub commands: CommandSet,
+    /// The low-power state used by [`Psram::sleep`](super::Psram::sleep), if
+    /// the part has one we support.
+    pub sleep: Option<SleepMode>,
+}
+
+impl PsramPart {
+    /// Does this part match the given ID?
+    pub fn matches(&self, id: &PsramId) -> bool {
+        id.manufacturer() == self.manufacturer
+            && id.kgd() == self.kgd
+            && id.eid() & self.eid_mask == self.eid
+    }
+}
+
+/// Mask selecting the density bits of the EID.
+const DENSITY_MASK: u8 = 0b1110_0000;
+
+/// Known parts, in match order. The first entry whose IDs match wins.
+pub static PARTS: &[PsramPart] = &[
+    PsramPart {
+        family: IdFamily::ApMemory,
+        name: "APS6404L / LY68L6400 / ESP-PSRAM64H",
+        manufacturer: 0x0D,
+        kgd: 0x5D,
+        eid_mask: DENSITY_MASK,
+        eid: 0b010 << 5,
+        size: 8 * 1024 * 1024,
+        page_size: 1024,
+        params: APS_PARAMS,
+        commands: APS_COMMANDS,
+        sleep: Some(APS_HALF_SLEEP),
+    },
+    // Some early 64 Mbit dies report an EID of 0x26, which has the 32 Mbit
+    // density bits set.
+    PsramPart {
+        family: IdFamily::ApMemory,
+        name: "APS6404L (EID 0x26)",
+        manufacturer: 0x0D,
+        kgd: 0x5D,
+        eid_mask: 0xFF,
+        eid: 0x26,
+        size: 8 * 1024 * 1024,
+        page_size: 1024,
+        params: APS_PARAMS,
+        commands: APS_COMMANDS,
+        sleep: Some(APS_HALF_SLEEP),
+    },
+    // Any other AP Memory die with the 32 Mbit density bits, which the
+    // driver has always treated as 4 MiB. This must come after the EID 0x26
+    // entry above.
+    PsramPart {
+        family: IdFamily::ApMemory,
+        name: "AP Memory 32 Mbit",
+        manufacturer: 0x0D,
+        kgd: 0x5D,
+        eid_mask: DENSITY_MASK,
+        eid: 0b001 << 5,
+        size: 4 * 1024 * 1024,
+        page_size: 1024,
+        params: APS_PARAMS,
+        commands: APS_COMMANDS,
+        sleep: Some(APS_HALF_SLEEP),
+    },
+    PsramPart {
+        family: IdFamily::ApMemory,
+        name: "APS1604M",
+        manufacturer: 0x0D,
+        kgd: 0x5D,
+        eid_mask: DENSITY_MASK,
+        eid: 0b000 << 5,
+        size: 2 * 1024 * 1024,
+        page_size: 1024,
+        params: APS_PARAMS,
+        commands: APS_COMMANDS,
+        sleep: Some(APS_HALF_SLEEP),
+    },
+    PsramPart {
+        family: IdFamily::Issi,
+        name: "IS66WVS4M8",
+        manufacturer: 0x9D,
+        kgd: 0x5D,
+        eid_mask: DENSITY_MASK,
+        eid: 0b001 << 5,
+        size: 4 * 1024 * 1024,
+        page_size: 1024,
+        params: ChipParams {
+            max_clock_hz: 104_000_000,
+            tcem_ns: 8_000,
+            min_deselect_ns: 18,
+        },
+        commands: APS_COMMANDS,
+        // Deep power-down on this part has not been tried, so it is left out.
+        sleep: None,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(manufacturer: u8, eid: u8) -> PsramId {
+        PsramId {
+            raw: [manufacturer, 0x5D, eid, 0, 0, 0, 0, 0],
+        }
+    }
+
+    fn name(id: PsramId) -> &'static str {
+        id.device().part().expect("not in the table").name
+    }
+
+    #[test]
+    fn aps6404l() {
+        assert_eq!(
+            name(id(0x0D, 0b010 << 5)),
+            "APS6404L / LY68L6400 / ESP-PSRAM64H"
+        );
+        assert_eq!(id(0x0D, (0b010 << 5) | 0x1F).size(), Some(8 * 1024 * 1024));
+    }
+
+    #[test]
+    fn eid_0x26_is_64_mbit() {
+        assert_eq!(name(id(0x0D, 0x26)), "APS6404L (EID 0x26)");
+        assert_eq!(id(0x0D, 0x26).size(), Some(8 * 1024 * 1024));
+        // Any other EID with the same density bits is 32 Mbit.
+        for eid in [0x20, 0x25, 0x27, 0x3F] {
+            assert_eq!(name(id(0x0D, eid)), "AP Memory 32 Mbit");
+            assert_eq!(id(0x0D, eid).size(), Some(4 * 1024 * 1024));
+        }
+    }
+
+    #[test]
+    fn aps1604m() {
+        assert_eq!(name(id(0x0D, 0b000 << 5)), "APS1604M");
+        assert_eq!(id(0x0D, 0).size(), Some(2 * 1024 * 1024));
+    }
+
+    #[test]
+    fn issi() {
+        let part = id(0x9D, 0b001 << 5).device().part().unwrap();
+        assert_eq!(part.name, "IS66WVS4M8");
+        assert_eq!(part.family, IdFamily::Issi);
+        assert_eq!(part.size, 4 * 1024 * 1024);
+        // The ISSI entry only covers its one density.
+        let unknown = id(0x9D, 0b010 << 5);
+        assert_eq!(unknown.device(), PsramDevice::Unrecognised(unknown));
+    }
+
+    #[test]
+    fn unknown_densities_are_unrecognised() {
+        for bits in 0b011..=0b111 {
+            let unknown = id(0x0D, bits << 5);
+            assert_eq!(unknown.device(), PsramDevice::Unrecognised(unknown));
+            assert_eq!(
+                unknown.device().known(),
+                Err(PsramError::UnsupportedDevice(unknown))
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_kgd_is_unrecognised() {
+        let mut unknown = id(0x0D, 0b010 << 5);
+        unknown.raw[1] = 0x55;
+        assert_eq!(unknown.device(), PsramDevice::Unrecognised(unknown));
+    }
+
+    #[test]
+    fn nothing_attached() {
+        for byte in [0x00, 0xFF] {
+            let floating = PsramId { raw: [byte; 8] };
+            assert_eq!(floating.device(), PsramDevice::NotPresent);
+            assert_eq!(floating.device().known(), Err(PsramError::NotDetected));
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Byte-level access to the PSRAM through QMI direct mode, bypassing XIP.
+//!
+//! This is slow, but useful for bring-up, for checking what is really in the
+//! PSRAM when the XIP cache may be hiding it, and for reaching the chip while
+//! M1 is configured in a way that does not map it. Direct-mode accesses do not
+//! go through the XIP cache, so cached copies of the same addresses are not
+//! updated.
+//!
+//! Transfers run at the same slow SCK as the other direct-mode commands, not
+//! at M1's clock. Direct mode samples read data without M1's RX delay, which
+//! is only safe while SCK is well below what the part can do.
+
+use core::ops::Range;
+
+use rp235x_hal::gpio::{PinId, PullType};
+
+use super::{Psram, PsramError, PsramPart, QmiBus, Width, COMMAND_CLKDIV};
+
+/// Bytes sent before the data of a quad read: command, address and wait
+/// cycles. Writes send fewer, but we use the same budget for both.
+const HEADER_BYTES: usize = 7;
+
+/// Rough number of clk_sys cycles of software overhead per byte transferred,
+/// while SCK is stopped with CS still asserted.
+const OVERHEAD_CYCLES_PER_BYTE: u64 = 64;
+
+/// Wait cycles for a quad read, as whole bytes at quad width.
+const READ_WAIT_BYTES: usize = 3;
+
+/// The XIP address space, which includes flash and the PSRAM itself.
+const XIP_SPACE: Range<usize> = 0x1000_0000..0x2000_0000;
+
+impl<I: PinId, P: PullType> Psram<I, P> {
+    /// Read `buf.len()` bytes starting at byte offset `addr` using direct mode.
+    ///
+    /// `buf` must be in SRAM: nothing in the XIP space, including the PSRAM
+    /// itself, can be accessed while QMI is in direct mode.
+    pub fn direct_read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), PsramError> {
+        self.check_direct(addr, buf.as_ptr() as usize, buf.len())?;
+        let (part, clock_hz) = (self.info.part, self.clock_hz);
+        self.parked(|psram| read_bursts(&mut psram.qmi, part, clock_hz, addr, buf, read_burst))
+    }
+
+    /// Write `data` starting at byte offset `addr` using direct mode.
+    ///
+    /// `data` must be in SRAM: nothing in the XIP space, including flash and
+    /// the PSRAM itself, can be accessed while QMI is in direct mode.
+    pub fn direct_write(&mut self, addr: u32, data: &[u8]) -> Result<(), PsramError> {
+        self.check_direct(addr, data.as_ptr() as usize, data.len())?;
+        let (part, clock_hz) = (self.info.part, self.clock_hz);
+        self.parked(|psram| write_bursts(&mut psram.qmi, part, clock_hz, addr, data, write_burst))
+    }
+
+    fn check_direct(&self, addr: u32, buf: usize, len: usize) -> Result<(), PsramError> {
+        self.check_awake()?;
+        let end = u64::from(addr) + len as u64;
+        if end > u64::from(self.info.size()) {
+            return Err(PsramError::OutOfBounds);
+        }
+        if len != 0 && (XIP_SPACE.contains(&buf) || XIP_SPACE.contains(&(buf + len - 1))) {
+            return Err(PsramError::BufferNotInRam);
+        }
+        Ok(())
+    }
+}
+
+/// Read `buf` from byte offset `addr`, a burst at a time. `burst` is
+/// [`read_burst`], or in tests its `_direct` half.
+fn read_bursts<B: QmiBus>(
+    bus: &mut B,
+    part: &PsramPart,
+    clock_hz: u32,
+    addr: u32,
+    buf: &mut [u8],
+    burst: fn(&mut B, u8, u32, &mut [u8]) -> Result<(), PsramError>,
+) -> Result<(), PsramError> {
+    for (addr, range) in bursts(part, clock_hz, addr, buf.len()) {
+        burst(bus, part.commands.quad_read, addr, &mut buf[range])?;
+    }
+    Ok(())
+}
+
+/// Write `data` at byte offset `addr`, a burst at a time. `burst` is
+/// [`write_burst`], or in tests its `_direct` half.
+fn write_bursts<B: QmiBus>(
+    bus: &mut B,
+    part: &PsramPart,
+    clock_hz: u32,
+    addr: u32,
+    data: &[u8],
+    burst: fn(&mut B, u8, u32, &[u8]) -> Result<(), PsramError>,
+) -> Result<(), PsramError> {
+    for (addr, range) in bursts(part, clock_hz, addr, data.len()) {
+        burst(bus, part.commands.quad_write, addr, &data[range])?;
+    }
+    Ok(())
+}
+
+/// Split a transfer of `len` bytes at `addr` into bursts, giving the address
+/// of each and its range within the buffer.
+fn bursts(
+    part: &PsramPart,
+    clock_hz: u32,
+    addr: u32,
+    len: usize,
+) -> impl Iterator<Item = (u32, Range<usize>)> + '_ {
+    let mut done = 0;
+    core::iter::from_fn(move || {
+        if done == len {
+            return None;
+        }
+        let start = addr + done as u32;
+        let burst = burst_len(part, clock_hz, start, len - done);
+        let range = done..done + burst;
+        done += burst;
+        Some((start, range))
+    })
+}
+
+/// How many bytes to transfer in one burst starting at `addr`.
+///
+/// A burst must not cross a page, and must keep CS asserted for no longer
+/// than tCEM. We stop SCK between bytes while software feeds the FIFO, so
+/// that time is counted too. At very low clk_sys not even one byte fits,
+/// and we do one byte at a time regardless.
+fn burst_len(part: &PsramPart, clock_hz: u32, addr: u32, remaining: usize) -> usize {
+    let to_page_end = (part.page_size - addr % part.page_size) as usize;
+
+    let cycles_per_byte = 2 * u64::from(COMMAND_CLKDIV) + OVERHEAD_CYCLES_PER_BYTE;
+    let tcem_cycles = u64::from(part.params.tcem_ns) * u64::from(clock_hz) / 1_000_000_000;
+    let burst = ((tcem_cycles / cycles_per_byte) as usize)
+        .saturating_sub(HEADER_BYTES)
+        .max(1);
+
+    remaining.min(to_page_end).min(burst)
+}
+
+/// Send a quad command and 24-bit address. CS must already be asserted.
+#[inline(always)]
+fn send_header<B: QmiBus>(bus: &mut B, command: u8, addr: u32) -> Result<(), PsramError> {
+    bus.send(Width::Quad, command)?;
+    bus.send(Width::Quad, (addr >> 16) as u8)?;
+    bus.send(Width::Quad, (addr >> 8) as u8)?;
+    bus.send(Width::Quad, addr as u8)
+}
+
+/// Read one burst. The PSRAM must be in QPI mode.
+fn read_burst<B: QmiBus>(
+    bus: &mut B,
+    command: u8,
+    addr: u32,
+    buf: &mut [u8],
+) -> Result<(), PsramError> {
+    critical_section::with(|_cs| read_burst_direct(bus, command, addr, buf))
+}
+
+#[link_section = ".ram_func"]
+#[inline(never)]
+fn read_burst_direct<B: QmiBus>(
+    bus: &mut B,
+    command: u8,
+    addr: u32,
+    buf: &mut [u8],
+) -> Result<(), PsramError> {
+    bus.enable_direct(COMMAND_CLKDIV, false);
+
+    let result = read_selected(bus, command, addr, buf);
+
+    bus.disable_direct();
+    result
+}
+
+#[inline(always)]
+fn read_selected<B: QmiBus>(
+    bus: &mut B,
+    command: u8,
+    addr: u32,
+    buf: &mut [u8],
+) -> Result<(), PsramError> {
+    bus.wait_idle()?;
+    bus.select(true);
+    send_header(bus, command, addr)?;
+    for _ in 0..READ_WAIT_BYTES {
+        bus.receive(Width::Quad)?;
+    }
+    for byte in buf.iter_mut() {
+        *byte = bus.receive(Width::Quad)?;
+    }
+    Ok(())
+}
+
+/// Write one burst. The PSRAM must be in QPI mode.
+fn write_burst<B: QmiBus>(
+    bus: &mut B,
+    command: u8,
+    addr: u32,
+    data: &[u8],
+) -> Result<(), PsramError> {
+    critical_section::with(|_cs| write_burst_direct(bus, command, addr, data))
+}
+
+#[link_section = ".ram_func"]
+#[inline(never)]
+fn write_burst_direct<B: QmiBus>(
+    bus: &mut B,
+    command: u8,
+    addr: u32,
+    data: &[u8],
+) -> Result<(), PsramError> {
+    bus.enable_direct(COMMAND_CLKDIV, false);
+
+    let result = write_selected(bus, command, addr, data);
+
+    bus.disable_direct();
+    result
+}
+
+#[inline(always)]
+fn write_selected<B: QmiBus>(
+    bus: &mut B,
+    command: u8,
+    addr: u32,
+    data: &[u8],
+) -> Result<(), PsramError> {
+    bus.wait_idle()?;
+    bus.select(true);
+    send_header(bus, command, addr)?;
+    for &byte in data {
+        bus.send(Width::Quad, byte)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psram::mock::{Op, RecordingBus};
+    use crate::psram::sim::PsramModel;
+    use crate::psram::{configure_qpi_direct, PsramTiming, PARTS};
+
+    // As elsewhere, these use the `_direct` halves of the burst functions,
+    // which don't need the hardware spinlocks.
+
+    const CLOCK_HZ: u32 = 150_000_000;
+
+    /// The most bytes one burst carries at `CLOCK_HZ`: 1200 cycles of tCEM,
+    /// at 84 cycles a byte, less the 7 header bytes.
+    const MAX_BURST: usize = 7;
+
+    /// An APS6404L in QPI mode, as `psram_init` leaves it.
+    fn chip() -> PsramModel<4096> {
+        let mut chip = PsramModel::aps6404l();
+        let timing = PsramTiming::compute(CLOCK_HZ, &PARTS[0].params).unwrap();
+        configure_qpi_direct(&mut chip, &PARTS[0], &timing).unwrap();
+        chip
+    }
+
+    fn write<B: QmiBus>(bus: &mut B, addr: u32, data: &[u8]) {
+        write_bursts(bus, &PARTS[0], CLOCK_HZ, addr, data, write_burst_direct).unwrap();
+    }
+
+    fn read<B: QmiBus>(bus: &mut B, addr: u32, buf: &mut [u8]) {
+        read_bursts(bus, &PARTS[0], CLOCK_HZ, addr, buf, read_burst_direct).unwrap();
+    }
+
+    /// How many times CS was asserted.
+    fn selections<const N: usize>(bus: &RecordingBus<N>) -> usize {
+        bus.ops()
+            .iter()
+            .filter(|&&op| op == Op::Select(true))
+            .count()
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut chip = chip();
+        let data: [u8; 100] = core::array::from_fn(|i| i as u8 ^ 0x5A);
+        write(&mut chip, 0x123, &data);
+        assert_eq!(chip.memory()[0x123..0x123 + 100], data);
+
+        let mut buf = [0; 100];
+        read(&mut chip, 0x123, &mut buf);
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn bursts_stop_at_page_boundaries() {
+        assert!(bursts(&PARTS[0], CLOCK_HZ, 1020, 8).eq([(1020, 0..4), (1024, 4..8)]));
+
+        let mut bus = RecordingBus::<256>::new();
+        write(&mut bus, 1020, &[0; 8]);
+        assert_eq!(selections(&bus), 2);
+
+        // The model wraps a burst within its page, so an unsplit write would
+        // land at the start of the first page.
+        let mut chip = chip();
+        write(&mut chip, 1020, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(chip.memory()[1020..1028], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(chip.memory()[..4], [0; 4]);
+        let mut buf = [0; 8];
+        read(&mut chip, 1020, &mut buf);
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn bursts_stop_at_tcem() {
+        assert_eq!(burst_len(&PARTS[0], CLOCK_HZ, 0, usize::MAX), MAX_BURST);
+
+        let lengths = bursts(&PARTS[0], CLOCK_HZ, 0, 100).map(|(_, range)| range.len());
+        assert!(lengths.eq([MAX_BURST; 14].into_iter().chain([2])));
+
+        let mut bus = RecordingBus::<1024>::new();
+        let mut buf = [0; 100];
+        read(&mut bus, 0, &mut buf);
+        assert_eq!(selections(&bus), 15);
+    }
+
+    #[test]
+    fn bursts_fit_in_tcem() {
+        let part = &PARTS[0];
+        let cycles_per_byte = 2 * u64::from(COMMAND_CLKDIV) + OVERHEAD_CYCLES_PER_BYTE;
+        for clock_hz in [
+            100_000_000,
+            150_000_000,
+            200_000_000,
+            300_000_000,
+            400_000_000,
+        ] {
+            let burst = burst_len(part, clock_hz, 0, usize::MAX);
+            let selected_ns = (burst + HEADER_BYTES) as u64 * cycles_per_byte * 1_000_000_000
+                / u64::from(clock_hz);
+            assert!(burst > 1, "{clock_hz} Hz");
+            assert!(
+                selected_ns <= u64::from(part.params.tcem_ns),
+                "{clock_hz} Hz"
+            );
+        }
+        // Below that we do a byte at a time, even if it overruns.
+        assert_eq!(burst_len(part, 12_000_000, 0, usize::MAX), 1);
+    }
+
+    #[test]
+    fn read_header_then_wait_bytes() {
+        let mut bus = RecordingBus::<64>::new().respond(&[0xAA, 0xBB, 0xCC, 1, 2]);
+        let mut buf = [0; 2];
+        read_burst_direct(&mut bus, 0xEB, 0x01_2345, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+
+        let mut expected = vec![
+            Op::EnableDirect {
+                clkdiv: COMMAND_CLKDIV,
+                auto_cs1n: false,
+            },
+            Op::WaitIdle,
+            Op::Select(true),
+        ];
+        for data in [0xEB, 0x01, 0x23, 0x45] {
+            expected.extend([
+                Op::WaitIdle,
+                Op::Send {
+                    width: Width::Quad,
+                    data,
+                },
+                Op::WaitIdle,
+            ]);
+        }
+        // Three wait bytes, then the two data bytes.
+        for _ in 0..READ_WAIT_BYTES + 2 {
+            expected.extend([Op::Receive { width: Width::Quad }, Op::WaitIdle]);
+        }
+        expected.push(Op::DisableDirect);
+        assert_eq!(bus.ops(), &expected[..]);
+    }
+}
@@ -0,0 +1,484 @@
+//! Bulk copies between SRAM and the PSRAM using a DMA channel.
+//!
+//! Transfers go through the uncached PSRAM alias, so they neither thrash the
+//! XIP cache nor are slowed by it. When the region was created from the cached
+//! alias, the lines covering the transfer are cleaned (and invalidated, when
+//! writing) first, so the CPU and the DMA see the same data.
+//!
+//! Both ends are copied a word at a time when they share the same alignment,
+//! with any unaligned head and tail copied by the CPU; otherwise the DMA
+//! copies a byte at a time.
+//!
+//! The async copies sleep until the transfer completes. For them to be woken,
+//! [`on_dma_irq0`] must be called from the `DMA_IRQ_0` handler, and that
+//! interrupt unmasked.
+
+use core::future::Future;
+use core::ops::Range;
+use core::pin::Pin as FuturePin;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+use futures::task::AtomicWaker;
+use rp235x_hal::dma::SingleChannel;
+use rp235x_hal::gpio::{PinId, PullType};
+use rp235x_hal::timer::{Instant, Timer, TimerDevice};
+
+use super::{Psram, PsramAlias, PsramError, PsramRegion};
+
+/// The XIP address space, which includes flash and the PSRAM itself.
+const XIP_SPACE: Range<usize> = 0x1000_0000..0x2000_0000;
+
+/// `TREQ_SEL` value for an unpaced transfer.
+const TREQ_UNPACED: u8 = 0x3F;
+
+/// Number of DMA channels.
+const CHANNELS: usize = 16;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// The task waiting on each channel.
+static WAKERS: [AtomicWaker; CHANNELS] = [NO_WAKER; CHANNELS];
+
+/// Channels with an async copy in progress.
+static LISTENING: AtomicU32 = AtomicU32::new(0);
+
+/// Wake any async copies that have finished.
+///
+/// Call this from the `DMA_IRQ_0` handler. Only the interrupts of channels
+/// with an async copy in progress are acknowledged, so the handler can be
+/// shared with other users of the DMA.
+pub fn on_dma_irq0() {
+    // Safety: INTS0 is write-one-to-clear, so we only touch our own bits.
+    let dma = unsafe { &*rp235x_hal::pac::DMA::ptr() };
+    let done = dma.ints0().read().bits() & LISTENING.load(Ordering::Acquire);
+    if done == 0 {
+        return;
+    }
+    dma.ints0().write(|w| unsafe { w.bits(done) });
+    for (ch, waker) in WAKERS.iter().enumerate() {
+        if done & (1 << ch) != 0 {
+            waker.wake();
+        }
+    }
+}
+
+/// How long a copy took.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct CopyStats {
+    /// Bytes copied.
+    pub bytes: usize,
+    /// Time taken, including cache maintenance, in microseconds.
+    pub micros: u64,
+}
+
+impl CopyStats {
+    /// Throughput in bytes per second, or `None` if the copy was too quick to
+    /// measure.
+    pub fn bytes_per_second(&self) -> Option<u64> {
+        (self.micros != 0).then(|| self.bytes as u64 * 1_000_000 / self.micros)
+    }
+}
+
+/// Which way data is moving, which decides the cache maintenance needed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToPsram,
+    FromPsram,
+}
+
+/// A DMA channel lent to a [`Psram`] for copies.
+///
+/// This borrows the [`Psram`], so the PSRAM cannot be reset or released while
+/// it is in use.
+pub struct PsramDma<'p, I: PinId, P: PullType, CH: SingleChannel, D: TimerDevice> {
+    psram: &'p Psram<I, P>,
+    ch: CH,
+    timer: Timer<D>,
+}
+
+impl<I: PinId, P: PullType> Psram<I, P> {
+    /// Use `ch` for DMA copies to and from the PSRAM. `timer` is used to
+    /// measure throughput.
+    pub fn dma<CH: SingleChannel, D: TimerDevice>(
+        &self,
+        ch: CH,
+        timer: Timer<D>,
+    ) -> PsramDma<'_, I, P, CH, D> {
+        PsramDma {
+            psram: self,
+            ch,
+            timer,
+        }
+    }
+}
+
+impl<I: PinId, P: PullType, CH: SingleChannel, D: TimerDevice> PsramDma<'_, I, P, CH, D> {
+    /// Give the DMA channel back.
+    pub fn free(self) -> CH {
+        self.ch
+    }
+
+    /// Copy `src` into `region`, starting `offset` bytes in, and wait for it
+    /// to finish.
+    ///
+    /// `src` must be in SRAM, not flash or the PSRAM, so it cannot overlap
+    /// the region.
+    pub fn copy_to(
+        &mut self,
+        region: &mut PsramRegion<'_>,
+        offset: usize,
+        src: &[u8],
+    ) -> Result<CopyStats, PsramError> {
+        let start = self.timer.get_counter();
+        check_ram(src.as_ptr() as usize, src.len())?;
+        let dst = self.prepare(region, offset, src.len(), Direction::ToPsram)?;
+        let job = Job::copy(src.as_ptr() as usize, dst, src.len());
+        job.run(&self.ch, false).wait();
+        Ok(self.stats(start, src.len()))
+    }
+
+    /// Copy from `region`, starting `offset` bytes in, into `dst`, and wait
+    /// for it to finish.
+    ///
+    /// `dst` must be in SRAM, not the PSRAM, so it cannot overlap the region.
+    pub fn copy_from(
+        &mut self,
+        region: &PsramRegion<'_>,
+        offset: usize,
+        dst: &mut [u8],
+    ) -> Result<CopyStats, PsramError> {
+        let start = self.timer.get_counter();
+        check_ram(dst.as_ptr() as usize, dst.len())?;
+        let src = self.prepare(region, offset, dst.len(), Direction::FromPsram)?;
+        let job = Job::copy(src, dst.as_mut_ptr() as usize, dst.len());
+        job.run(&self.ch, false).wait();
+        Ok(self.stats(start, dst.len()))
+    }
+
+    /// Set `len` bytes of `region`, starting `offset` bytes in, to `value`,
+    /// and wait for it to finish.
+    pub fn fill(
+        &mut self,
+        region: &mut PsramRegion<'_>,
+        offset: usize,
+        len: usize,
+        value: u8,
+    ) -> Result<CopyStats, PsramError> {
+        let start = self.timer.get_counter();
+        let dst = self.prepare(region, offset, len, Direction::ToPsram)?;
+        let pattern = u32::from_ne_bytes([value; 4]);
+        let job = Job::fill(&pattern, dst, len);
+        job.run(&self.ch, false).wait();
+        Ok(self.stats(start, len))
+    }
+
+    /// As [`PsramDma::copy_to`], but sleeps until the DMA finishes. Dropping
+    /// the future aborts the copy.
+    pub async fn copy_to_async(
+        &mut self,
+        region: &mut PsramRegion<'_>,
+        offset: usize,
+        src: &[u8],
+    ) -> Result<CopyStats, PsramError> {
+        let start = self.timer.get_counter();
+        check_ram(src.as_ptr() as usize, src.len())?;
+        let dst = self.prepare(region, offset, src.len(), Direction::ToPsram)?;
+        let job = Job::copy(src.as_ptr() as usize, dst, src.len());
+        job.run(&self.ch, true).await;
+        Ok(self.stats(start, src.len()))
+    }
+
+    /// As [`PsramDma::copy_from`], but sleeps until the DMA finishes.
+    /// Dropping the future aborts the copy.
+    pub async fn copy_from_async(
+        &mut self,
+        region: &PsramRegion<'_>,
+        offset: usize,
+        dst: &mut [u8],
+    ) -> Result<CopyStats, PsramError> {
+        let start = self.timer.get_counter();
+        check_ram(dst.as_ptr() as usize, dst.len())?;
+        let src = self.prepare(region, offset, dst.len(), Direction::FromPsram)?;
+        let job = Job::copy(src, dst.as_mut_ptr() as usize, dst.len());
+        job.run(&self.ch, true).await;
+        Ok(self.stats(start, dst.len()))
+    }
+
+    /// As [`PsramDma::fill`], but sleeps until the DMA finishes. Dropping the
+    /// future aborts the fill.
+    pub async fn fill_async(
+        &mut self,
+        region: &mut PsramRegion<'_>,
+        offset: usize,
+        len: usize,
+        value: u8,
+    ) -> Result<CopyStats, PsramError> {
+        let start = self.timer.get_counter();
+        let dst = self.prepare(region, offset, len, Direction::ToPsram)?;
+        let pattern = u32::from_ne_bytes([value; 4]);
+        let job = Job::fill(&pattern, dst, len);
+        job.run(&self.ch, true).await;
+        Ok(self.stats(start, len))
+    }
+
+    /// Check the copy fits in the region, do any cache maintenance, and
+    /// return the uncached address to copy to or from.
+    fn prepare(
+        &self,
+        region: &PsramRegion<'_>,
+        offset: usize,
+        len: usize,
+        direction: Direction,
+    ) -> Result<usize, PsramError> {
+        self.psram.check_awake()?;
+        if offset
+            .checked_add(len)
+            .map_or(true, |end| end > region.len())
+        {
+            return Err(PsramError::OutOfBounds);
+        }
+        let start = region.addresses().start + offset;
+        let range = start..start + len;
+        if region.alias() == PsramAlias::Cached {
+            match direction {
+                // Dirty lines must not be written back over the new data
+                // later, and stale lines must not be read.
+                Direction::ToPsram => self.psram.clean_invalidate_range(range)?,
+                Direction::FromPsram => self.psram.clean_range(range)?,
+            }
+        }
+        Ok(PsramAlias::Uncached.base() + region.offset() as usize + offset)
+    }
+
+    fn stats(&self, start: Instant, bytes: usize) -> CopyStats {
+        let micros = (self.timer.get_counter() - start).to_micros();
+        CopyStats { bytes, micros }
+    }
+}
+
+/// Fail with [`PsramError::BufferNotInRam`] if any of the `len` bytes at
+/// `buf` are in the XIP space, where they could overlap the PSRAM.
+fn check_ram(buf: usize, len: usize) -> Result<(), PsramError> {
+    if len != 0 && (XIP_SPACE.contains(&buf) || XIP_SPACE.contains(&(buf + len - 1))) {
+        return Err(PsramError::BufferNotInRam);
+    }
+    Ok(())
+}
+
+/// A copy, split into the parts done by the CPU and by the DMA.
+struct Job {
+    src: usize,
+    dst: usize,
+    /// Set when every byte is `value`, read from the word at `src`.
+    fill: Option<u8>,
+    /// Bytes copied by the CPU before the DMA part.
+    head: usize,
+    /// Number of DMA transfers.
+    count: usize,
+    /// Size of each DMA transfer, 1 or 4 bytes.
+    size: usize,
+    /// Bytes copied by the CPU after the DMA part.
+    tail: usize,
+}
+
+impl Job {
+    fn copy(src: usize, dst: usize, len: usize) -> Self {
+        if (src ^ dst) & 3 != 0 {
+            return Job {
+                src,
+                dst,
+                fill: None,
+                head: 0,
+                count: len,
+                size: 1,
+                tail: 0,
+            };
+        }
+        Job::split(src, dst, None, len)
+    }
+
+    /// Fill from `pattern`, which holds the same byte four times.
+    fn fill(pattern: &u32, dst: usize, len: usize) -> Self {
+        let value = pattern.to_ne_bytes()[0];
+        Job::split(pattern as *const u32 as usize, dst, Some(value), len)
+    }
+
+    /// Use word transfers once `dst` is aligned.
+    fn split(src: usize, dst: usize, fill: Option<u8>, len: usize) -> Self {
+        let head = ((4 - dst % 4) % 4).min(len);
+        let count = (len - head) / 4;
+        let tail = len - head - count * 4;
+        Job {
+            src,
+            dst,
+            fill,
+            head,
+            count,
+            size: 4,
+            tail,
+        }
+    }
+
+    /// Copy the head and tail, and start the DMA on the rest. If `notify` is
+    /// set, the channel raises `DMA_IRQ_0` when it finishes.
+    fn run<CH: SingleChannel>(self, ch: &CH, notify: bool) -> Running<'_, CH> {
+        let dma_len = self.count * self.size;
+        let after = self.head + dma_len;
+        // Safety: the caller has checked both ends are valid for `len` bytes,
+        // and that one is in the PSRAM and the other outside the XIP space,
+        // so they cannot overlap.
+        unsafe {
+            match self.fill {
+                Some(value) => {
+                    set_bytes(self.dst, value, self.head);
+                    set_bytes(self.dst + after, value, self.tail);
+                }
+                None => {
+                    copy_bytes(self.src, self.dst, self.head);
+                    copy_bytes(self.src + after, self.dst + after, self.tail);
+                }
+            }
+        }
+        if self.count == 0 {
+            return Running {
+                ch,
+                done: true,
+                notify: false,
+            };
+        }
+        let read_addr = match self.fill {
+            Some(_) => self.src,
+            None => self.src + self.head,
+        };
+        let id = ch.id();
+        if notify {
+            LISTENING.fetch_or(1 << id, Ordering::AcqRel);
+            // Safety: we only touch our own channel's bit.
+            let dma = unsafe { &*rp235x_hal::pac::DMA::ptr() };
+            critical_section::with(|_| {
+                dma.inte0()
+                    .modify(|r, w| unsafe { w.bits(r.bits() | 1 << id) });
+            });
+        }
+        // Make sure all our writes to the source are visible to the DMA.
+        fence(Ordering::SeqCst);
+
+        let regs = ch.ch();
+        regs.ch_read_addr()
+            .write(|w| unsafe { w.bits(read_addr as u32) });
+        regs.ch_write_addr()
+            .write(|w| unsafe { w.bits((self.dst + self.head) as u32) });
+        regs.ch_trans_count()
+            .write(|w| unsafe { w.bits(self.count as u32) });
+        regs.ch_ctrl_trig().write(|w| unsafe {
+            if self.size == 4 {
+                w.data_size().size_word();
+            } else {
+                w.data_size().size_byte();
+            }
+            w.incr_read().bit(self.fill.is_none());
+            w.incr_write().set_bit();
+            w.treq_sel().bits(TREQ_UNPACED);
+            // Chaining to ourselves disables chaining.
+            w.chain_to().bits(id);
+            w.irq_quiet().bit(!notify);
+            w.en().set_bit();
+            w
+        });
+        Running {
+            ch,
+            done: false,
+            notify,
+        }
+    }
+}
+
+/// Copy `len` bytes with the CPU.
+unsafe fn copy_bytes(src: usize, dst: usize, len: usize) {
+    core::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, len);
+}
+
+/// Set `len` bytes to `value` with the CPU.
+unsafe fn set_bytes(dst: usize, value: u8, len: usize) {
+    core::ptr::write_bytes(dst as *mut u8, value, len);
+}
+
+/// A DMA transfer in progress. It is aborted if dropped before completing, so
+/// the borrowed buffers are never written after we return.
+struct Running<'c, CH: SingleChannel> {
+    ch: &'c CH,
+    done: bool,
+    /// Whether the channel raises `DMA_IRQ_0` when it finishes.
+    notify: bool,
+}
+
+impl<CH: SingleChannel> Running<'_, CH> {
+    fn is_done(&mut self) -> bool {
+        if !self.done && self.ch.ch().ch_ctrl_trig().read().busy().bit_is_clear() {
+            self.done = true;
+            // Make sure we see everything the DMA wrote.
+            fence(Ordering::SeqCst);
+        }
+        self.done
+    }
+
+    fn wait(mut self) {
+        while !self.is_done() {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<CH: SingleChannel> Future for Running<'_, CH> {
+    type Output = ();
+
+    fn poll(mut self: FuturePin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Register before checking, so a completion in between still wakes us.
+        WAKERS[self.ch.id() as usize].register(cx.waker());
+        if self.is_done() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<CH: SingleChannel> Drop for Running<'_, CH> {
+    fn drop(&mut self) {
+        // Safety: we only touch our own channel's bits.
+        let dma = unsafe { &*rp235x_hal::pac::DMA::ptr() };
+        let mask = 1 << self.ch.id();
+        if !self.is_done() {
+            dma.chan_abort().write(|w| unsafe { w.bits(mask) });
+            while dma.chan_abort().read().bits() & mask != 0 {
+                core::hint::spin_loop();
+            }
+        }
+        if self.notify {
+            critical_section::with(|_| {
+                dma.inte0()
+                    .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+            });
+            dma.ints0().write(|w| unsafe { w.bits(mask) });
+            LISTENING.fetch_and(!mask, Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_must_be_outside_xip() {
+        // SRAM, and an empty buffer anywhere.
+        assert_eq!(check_ram(0x2000_0000, 4096), Ok(()));
+        assert_eq!(check_ram(0x1100_0000, 0), Ok(()));
+        // Flash, the PSRAM, and a buffer straddling the end of the XIP space.
+        for (buf, len) in [(0x1000_0000, 4), (0x1100_0000, 4), (0x1FFF_FFFE, 4)] {
+            assert_eq!(check_ram(buf, len), Err(PsramError::BufferNotInRam));
+        }
+    }
+}
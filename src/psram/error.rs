@@ -0,0 +1,40 @@
+use super::PsramId;
+
+/// Errors reported while bringing up or accessing the PSRAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PsramError {
+    /// Nothing answered the Read ID command.
+    NotDetected,
+    /// A device answered Read ID, but it is not in the parts table.
+    UnsupportedDevice(PsramId),
+    /// The QMI did not finish a direct-mode transfer in time.
+    BusTimeout,
+    /// The memory did not read back what was written after configuration.
+    VerifyFailed {
+        /// Offset into the PSRAM of the first mismatch.
+        offset: u32,
+    },
+    /// A calibration sweep found no timing that passed the pattern test.
+    CalibrationFailed,
+    /// An access fell outside the PSRAM.
+    OutOfBounds,
+    /// An access or erase was not aligned as it needed to be, such as an
+    /// erase not on an erase block boundary.
+    NotAligned,
+    /// The PSRAM is asleep, see [`Psram::sleep`](super::Psram::sleep).
+    Asleep,
+    /// The part has no low-power state we know how to use.
+    SleepUnsupported,
+    /// The other core did not park, or release, when asked.
+    LockoutFailed,
+    /// A [`PsramHeap`](super::PsramHeap) had no room for an allocation.
+    OutOfMemory,
+    /// A buffer is in the XIP address space. A direct-mode transfer cannot
+    /// reach it, and a DMA copy could overlap the PSRAM.
+    BufferNotInRam,
+    /// The system clock cannot be divided down to something the part supports.
+    ClockOutOfRange {
+        /// The clock that was requested.
+        clock_hz: u32,
+    },
+}
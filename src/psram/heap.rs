@@ -0,0 +1,261 @@
+//! Heaps in PSRAM.
+//!
+//! [`DualHeap`] is a global allocator split between SRAM and PSRAM. Small
+//! allocations are served from a heap in SRAM, which is fast, and large ones
+//! from a heap in PSRAM, which is big. When the preferred heap is full the
+//! other one is tried, so running out of SRAM only costs speed.
+//!
+//! [`PsramHeap`] is a PSRAM heap for explicit use, through the containers in
+//! [`PsramBox`](super::PsramBox) and friends, alongside an ordinary global
+//! allocator in SRAM.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: DualHeap = DualHeap::new(1024);
+//!
+//! unsafe {
+//!     ALLOCATOR.init_sram(addr_of_mut!(SRAM_HEAP) as usize, SRAM_HEAP_SIZE);
+//!     ALLOCATOR.init_psram(psram::PSRAM_BASE, psram.size() as usize);
+//! }
+//! ```
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Range;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use embedded_alloc::Heap;
+
+use super::PsramError;
+
+/// The XIP address space. Anything allocated there came from the PSRAM heap.
+const XIP_SPACE: Range<usize> = 0x1000_0000..0x2000_0000;
+
+/// Usage of one of the heaps in a [`DualHeap`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct HeapStats {
+    /// Bytes currently allocated.
+    pub used: usize,
+    /// Bytes currently free.
+    pub free: usize,
+    /// Most bytes ever allocated at once.
+    pub peak: usize,
+    /// Allocations served, including fallbacks.
+    pub allocations: usize,
+    /// Allocations served only because the other heap was full.
+    pub fallbacks: usize,
+}
+
+/// Usage of both heaps in a [`DualHeap`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct DualHeapStats {
+    pub sram: HeapStats,
+    pub psram: HeapStats,
+    /// Allocations which neither heap could serve.
+    pub failures: usize,
+}
+
+/// One heap and its counters.
+struct Tracked {
+    heap: Heap,
+    peak: AtomicUsize,
+    allocations: AtomicUsize,
+    fallbacks: AtomicUsize,
+}
+
+impl Tracked {
+    const fn empty() -> Self {
+        Tracked {
+            heap: Heap::empty(),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            fallbacks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate, counting a success as a fallback if `fallback` is set.
+    unsafe fn alloc(&self, layout: Layout, fallback: bool) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        if !ptr.is_null() {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            if fallback {
+                self.fallbacks.fetch_add(1, Ordering::Relaxed);
+            }
+            self.peak.fetch_max(self.heap.used(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            used: self.heap.used(),
+            free: self.heap.free(),
+            peak: self.peak.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            fallbacks: self.fallbacks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A global allocator with a heap in SRAM for allocations smaller than a
+/// threshold, and a heap in PSRAM for the rest.
+///
+/// Either heap may be left uninitialised, in which case everything goes to
+/// the other; that keeps the firmware running on a board whose PSRAM failed
+/// to initialise.
+pub struct DualHeap {
+    sram: Tracked,
+    psram: Tracked,
+    threshold: usize,
+    failures: AtomicUsize,
+}
+
+impl DualHeap {
+    /// Two empty heaps. Allocations of `threshold` bytes or more prefer the
+    /// PSRAM.
+    pub const fn new(threshold: usize) -> Self {
+        DualHeap {
+            sram: Tracked::empty(),
+            psram: Tracked::empty(),
+            threshold,
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Give the SRAM heap `size` bytes starting at `start`.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be unused, and this may only be called once.
+    pub unsafe fn init_sram(&self, start: usize, size: usize) {
+        self.sram.heap.init(start, size)
+    }
+
+    /// Give the PSRAM heap `size` bytes starting at `start`, which must be
+    /// in one of the PSRAM's aliases, usually [`PSRAM_BASE`](super::PSRAM_BASE).
+    ///
+    /// # Safety
+    ///
+    /// The PSRAM must be initialised, the memory unused, and this may only
+    /// be called once.
+    pub unsafe fn init_psram(&self, start: usize, size: usize) {
+        debug_assert!(XIP_SPACE.contains(&start));
+        self.psram.heap.init(start, size)
+    }
+
+    /// Size at which allocations start to prefer the PSRAM.
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Current usage of both heaps.
+    pub fn stats(&self) -> DualHeapStats {
+        DualHeapStats {
+            sram: self.sram.stats(),
+            psram: self.psram.stats(),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for DualHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (preferred, other) = if layout.size() >= self.threshold {
+            (&self.psram, &self.sram)
+        } else {
+            (&self.sram, &self.psram)
+        };
+        let ptr = preferred.alloc(layout, false);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        let ptr = other.alloc(layout, true);
+        if ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if XIP_SPACE.contains(&(ptr as usize)) {
+            self.psram.heap.dealloc(ptr, layout)
+        } else {
+            self.sram.heap.dealloc(ptr, layout)
+        }
+    }
+}
+
+/// A heap in PSRAM, which is only used by the containers given it.
+///
+/// ```ignore
+/// static PSRAM_HEAP: PsramHeap = PsramHeap::empty();
+///
+/// unsafe { PSRAM_HEAP.init(psram::PSRAM_BASE, psram.size() as usize) };
+/// let mut samples = PsramVec::with_capacity(&PSRAM_HEAP, 100_000)?;
+/// ```
+pub struct PsramHeap {
+    heap: Heap,
+}
+
+impl PsramHeap {
+    /// A heap with no memory; every allocation fails until
+    /// [`PsramHeap::init`] is called.
+    pub const fn empty() -> Self {
+        PsramHeap {
+            heap: Heap::empty(),
+        }
+    }
+
+    /// Give the heap `size` bytes starting at `start`, which must be in one
+    /// of the PSRAM's aliases, usually [`PSRAM_BASE`](super::PSRAM_BASE).
+    ///
+    /// # Safety
+    ///
+    /// The PSRAM must be initialised, the memory unused, and this may only
+    /// be called once.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        debug_assert!(XIP_SPACE.contains(&start));
+        self.heap.init(start, size)
+    }
+
+    /// Bytes currently allocated.
+    pub fn used(&self) -> usize {
+        self.heap.used()
+    }
+
+    /// Bytes currently free.
+    pub fn free(&self) -> usize {
+        self.heap.free()
+    }
+
+    /// Allocate memory for `layout`, which must not be zero sized.
+    pub(super) fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, PsramError> {
+        // Safety: callers never ask for zero-sized allocations.
+        NonNull::new(unsafe { self.heap.alloc(layout) }).ok_or(PsramError::OutOfMemory)
+    }
+
+    /// Resize an allocation. On failure the old one is left alone.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from this heap with `layout`, and `new_size` must
+    /// not be zero.
+    pub(super) unsafe fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, PsramError> {
+        NonNull::new(self.heap.realloc(ptr.as_ptr(), layout, new_size))
+            .ok_or(PsramError::OutOfMemory)
+    }
+
+    /// Free an allocation.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from this heap with `layout`.
+    pub(super) unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.heap.dealloc(ptr.as_ptr(), layout)
+    }
+}
@@ -0,0 +1,559 @@
+//! Parking the other core while this one has the QMI in direct mode.
+//!
+//! A critical section only keeps this core's interrupts away. The other core
+//! may still be running from flash or using the PSRAM, and both stop working
+//! while the QMI is in direct mode, or while M1 is being reprogrammed. So the
+//! driver asks the other core to park itself, spinning in RAM, until the work
+//! is done.
+//!
+//! The request goes over the SIO inter-core FIFO, using the same handshake as
+//! the Pico SDK's `multicore_lockout`:
+//!
+//! 1. This core sends [`LOCKOUT_START`].
+//! 2. The other core's FIFO interrupt handler, [`on_sio_fifo_irq`], masks its
+//!    interrupts, echoes [`LOCKOUT_START`], and spins in RAM.
+//! 3. This core does its work, then sends [`LOCKOUT_END`].
+//! 4. The other core echoes [`LOCKOUT_END`], unmasks its interrupts and
+//!    returns.
+//!
+//! The handshake itself is the pair of state machines [`Handshake`] and
+//! [`Parkee`], which are kept free of register access so they can be tested
+//! off target.
+//!
+//! If the other core does not answer in time, the request is still sitting in
+//! its FIFO, and it will park when it gets to it. So giving up on a park also
+//! sends [`LOCKOUT_END`], to release it straight away, and the echoes of both
+//! are discarded when they arrive rather than being taken as replies to a
+//! later request.
+
+use rp235x_hal::gpio::{PinId, PullType};
+use rp235x_hal::sio::SioFifo;
+
+use super::{Psram, PsramError};
+
+/// Sent to ask the other core to park, and echoed once it has.
+pub const LOCKOUT_START: u32 = 0x73A8_831E;
+
+/// Sent to release the other core, and echoed once it is running again.
+pub const LOCKOUT_END: u32 = 0x73A8_831F;
+
+/// How many times we poll the FIFO for the other core's reply before giving
+/// up. The other core may be in a critical section of its own, so this is
+/// far longer than the handshake itself takes.
+const REPLY_BUDGET: u32 = 10_000_000;
+
+/// The handshake, as seen by the core doing the direct-mode work.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Initiator {
+    /// The other core is running normally.
+    Idle,
+    /// [`LOCKOUT_START`] has been sent.
+    Parking,
+    /// The other core has echoed [`LOCKOUT_START`] and is parked.
+    Parked,
+    /// [`LOCKOUT_END`] has been sent.
+    Releasing,
+}
+
+impl Initiator {
+    /// Ask the other core to park. Returns the new state and the word to
+    /// send, or `None` if the other core is not idle.
+    #[inline(always)]
+    pub const fn park(self) -> Option<(Self, u32)> {
+        match self {
+            Initiator::Idle => Some((Initiator::Parking, LOCKOUT_START)),
+            _ => None,
+        }
+    }
+
+    /// Release the other core. Returns the new state and the word to send,
+    /// or `None` if the other core is not parked.
+    #[inline(always)]
+    pub const fn release(self) -> Option<(Self, u32)> {
+        match self {
+            Initiator::Parked => Some((Initiator::Releasing, LOCKOUT_END)),
+            _ => None,
+        }
+    }
+
+    /// Handle a word from the other core. Returns the new state, or `None`
+    /// if the word was not the reply we were waiting for.
+    #[inline(always)]
+    pub const fn receive(self, word: u32) -> Option<Self> {
+        match (self, word) {
+            (Initiator::Parking, LOCKOUT_START) => Some(Initiator::Parked),
+            (Initiator::Releasing, LOCKOUT_END) => Some(Initiator::Idle),
+            _ => None,
+        }
+    }
+}
+
+/// The handshake, as seen by the core being parked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Parkee {
+    Running,
+    Parked,
+}
+
+impl Parkee {
+    /// Handle a word from the initiator. Returns the new state and the word
+    /// to echo back, if any. Words which are not part of the handshake leave
+    /// the state alone and get no reply.
+    #[inline(always)]
+    pub const fn receive(self, word: u32) -> (Self, Option<u32>) {
+        match (self, word) {
+            (Parkee::Running, LOCKOUT_START) => (Parkee::Parked, Some(LOCKOUT_START)),
+            (Parkee::Parked, LOCKOUT_END) => (Parkee::Running, Some(LOCKOUT_END)),
+            _ => (self, None),
+        }
+    }
+}
+
+/// The initiator's side of the handshake, including any echoes still owed
+/// by the other core from attempts which timed out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Handshake {
+    state: Initiator,
+    stale: u8,
+}
+
+impl Handshake {
+    pub const fn new() -> Self {
+        Handshake {
+            state: Initiator::Idle,
+            stale: 0,
+        }
+    }
+
+    /// Where the handshake is up to.
+    pub const fn state(&self) -> Initiator {
+        self.state
+    }
+
+    /// How many echoes from abandoned attempts have yet to arrive.
+    pub const fn stale(&self) -> u8 {
+        self.stale
+    }
+
+    /// Start parking the other core. Returns the word to send, or `None` if
+    /// the other core is not idle, or has not yet caught up with an attempt
+    /// which timed out.
+    ///
+    /// Refusing while echoes are owed means no real reply can arrive among
+    /// them, and that at most two words are ever waiting in the other core's
+    /// FIFO.
+    pub fn park(&mut self) -> Option<u32> {
+        if self.stale != 0 {
+            return None;
+        }
+        let (state, word) = self.state.park()?;
+        self.state = state;
+        Some(word)
+    }
+
+    /// Start releasing the other core. Returns the word to send, or `None` if
+    /// it is not parked.
+    pub fn release(&mut self) -> Option<u32> {
+        let (state, word) = self.state.release()?;
+        self.state = state;
+        Some(word)
+    }
+
+    /// Handle a word from the other core. Returns `true` if it was the reply
+    /// we were waiting for. Stale echoes, and anything else, are discarded.
+    pub fn receive(&mut self, word: u32) -> bool {
+        if self.stale != 0 && matches!(word, LOCKOUT_START | LOCKOUT_END) {
+            self.stale -= 1;
+            return false;
+        }
+        match self.state.receive(word) {
+            Some(state) => {
+                self.state = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop waiting for a reply, and go back to idle. Returns a word to send,
+    /// if the other core needs telling.
+    pub fn give_up(&mut self) -> Option<u32> {
+        match self.state {
+            // It will park, and echo, when it reads the request, so release
+            // it straight after. That is two echoes to discard.
+            Initiator::Parking => {
+                self.state = Initiator::Idle;
+                self.stale += 2;
+                Some(LOCKOUT_END)
+            }
+            // It has the release already, and will echo it.
+            Initiator::Releasing => {
+                self.state = Initiator::Idle;
+                self.stale += 1;
+                None
+            }
+            Initiator::Idle | Initiator::Parked => None,
+        }
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The initiating side of the lockout, using this core's end of the SIO FIFO.
+///
+/// The FIFO should not be used for anything else while this has it.
+pub struct FifoLockout {
+    fifo: SioFifo,
+    handshake: Handshake,
+}
+
+impl FifoLockout {
+    pub fn new(fifo: SioFifo) -> Self {
+        FifoLockout {
+            fifo,
+            handshake: Handshake::new(),
+        }
+    }
+
+    /// Give the FIFO back.
+    pub fn free(self) -> SioFifo {
+        self.fifo
+    }
+
+    /// Park the other core, and wait until it has.
+    ///
+    /// This fails straight away if the other core has still not answered an
+    /// earlier attempt which timed out.
+    pub fn park(&mut self) -> Result<(), PsramError> {
+        // Pick up any echoes from an attempt which timed out.
+        while let Some(word) = self.fifo.read() {
+            self.handshake.receive(word);
+        }
+        let word = self.handshake.park().ok_or(PsramError::LockoutFailed)?;
+        self.exchange(word)
+    }
+
+    /// Release the other core, and wait until it is running again.
+    pub fn release(&mut self) -> Result<(), PsramError> {
+        let word = self.handshake.release().ok_or(PsramError::LockoutFailed)?;
+        self.exchange(word)
+    }
+
+    /// Send `word`, then wait for the reply. If the other core does not
+    /// answer, give up, telling it so if need be.
+    fn exchange(&mut self, word: u32) -> Result<(), PsramError> {
+        self.fifo.write_blocking(word);
+        for _ in 0..REPLY_BUDGET {
+            if let Some(reply) = self.fifo.read() {
+                if self.handshake.receive(reply) {
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(word) = self.handshake.give_up() {
+            self.fifo.write_blocking(word);
+        }
+        Err(PsramError::LockoutFailed)
+    }
+}
+
+/// Run `f` with the other core parked, if there is a `lockout`.
+///
+/// The other core is released even if `f` fails; `f`'s error takes priority.
+pub(super) fn parked<R>(
+    lockout: Option<&mut FifoLockout>,
+    f: impl FnOnce() -> Result<R, PsramError>,
+) -> Result<R, PsramError> {
+    let Some(lockout) = lockout else {
+        return f();
+    };
+    lockout.park()?;
+    let result = f();
+    let released = lockout.release();
+    result.and_then(|value| released.map(|()| value))
+}
+
+impl<I: PinId, P: PullType> Psram<I, P> {
+    /// Park the other core through `fifo` whenever the PSRAM is reset,
+    /// calibrated, put to sleep or accessed in direct mode.
+    ///
+    /// The other core must have [`on_sio_fifo_irq`] installed as its
+    /// `SIO_IRQ_FIFO` handler, with that interrupt unmasked, or these
+    /// operations fail with [`PsramError::LockoutFailed`].
+    pub fn set_lockout(&mut self, fifo: SioFifo) {
+        self.lockout = Some(FifoLockout::new(fifo));
+    }
+
+    /// Stop parking the other core, and give the FIFO back.
+    pub fn take_lockout(&mut self) -> Option<SioFifo> {
+        self.lockout.take().map(FifoLockout::free)
+    }
+
+    /// Run `f` with the other core parked, if a lockout is set.
+    ///
+    /// The lockout is taken out while `f` runs, so driver methods called from
+    /// `f` do not try to park the other core a second time.
+    pub(super) fn parked<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<R, PsramError>,
+    ) -> Result<R, PsramError> {
+        let mut lockout = self.lockout.take();
+        let result = parked(lockout.as_mut(), || f(self));
+        self.lockout = lockout;
+        result
+    }
+}
+
+/// Service a lockout request on this core.
+///
+/// Call this from the `SIO_IRQ_FIFO` handler of the core which is to be
+/// parked. It reads one word from the FIFO: if that is a lockout request the
+/// core is parked, spinning in RAM with its interrupts masked, until it is
+/// released. Anything else is returned for the caller to handle.
+pub fn on_sio_fifo_irq() -> Option<u32> {
+    // Safety: this core's end of the FIFO is only read here while the
+    // handler runs, and the status register is write-one-to-clear.
+    let sio = unsafe { &*rp235x_hal::pac::SIO::ptr() };
+    // Clear any overflow or underflow flags, which also raise the interrupt.
+    sio.fifo_st().write(|w| unsafe { w.bits(0xFF) });
+    if sio.fifo_st().read().vld().bit_is_clear() {
+        return None;
+    }
+    let word = sio.fifo_rd().read().bits();
+    let (state, Some(reply)) = Parkee::Running.receive(word) else {
+        return Some(word);
+    };
+
+    let interrupts = rp235x_hal::arch::interrupts_enabled();
+    rp235x_hal::arch::interrupt_disable();
+    park(sio, state, reply);
+    if interrupts {
+        // Safety: they were enabled when we were called.
+        unsafe { rp235x_hal::arch::interrupt_enable() };
+    }
+    None
+}
+
+/// Send `reply` to acknowledge the lockout, then spin until released.
+///
+/// Once the reply is sent the other core may put the QMI into direct mode,
+/// so from then on nothing in flash may be touched.
+#[link_section = ".ram_func"]
+#[inline(never)]
+fn park(sio: &rp235x_hal::pac::sio::RegisterBlock, state: Parkee, reply: u32) {
+    push(sio, reply);
+    let mut state = state;
+    while let Parkee::Parked = state {
+        while sio.fifo_st().read().vld().bit_is_clear() {
+            core::hint::spin_loop();
+        }
+        let (next, reply) = state.receive(sio.fifo_rd().read().bits());
+        state = next;
+        if let Some(reply) = reply {
+            push(sio, reply);
+        }
+    }
+}
+
+/// Write `word` to the other core, waiting for space in the FIFO.
+#[inline(always)]
+fn push(sio: &rp235x_hal::pac::sio::RegisterBlock, word: u32) {
+    while sio.fifo_st().read().rdy().bit_is_clear() {
+        core::hint::spin_loop();
+    }
+    sio.fifo_wr().write(|w| unsafe { w.bits(word) });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Both cores' ends of the FIFO, and the other core's handler.
+    struct Cores {
+        us: Handshake,
+        them: Parkee,
+        to_them: VecDeque<u32>,
+        to_us: VecDeque<u32>,
+    }
+
+    impl Cores {
+        fn new() -> Self {
+            Cores {
+                us: Handshake::new(),
+                them: Parkee::Running,
+                to_them: VecDeque::new(),
+                to_us: VecDeque::new(),
+            }
+        }
+
+        fn send(&mut self, word: Option<u32>) {
+            self.to_them.push_back(word.expect("nothing to send"));
+        }
+
+        /// Let the other core handle everything sent to it so far.
+        fn run_them(&mut self) {
+            while let Some(word) = self.to_them.pop_front() {
+                let (state, reply) = self.them.receive(word);
+                self.them = state;
+                self.to_us.extend(reply);
+            }
+        }
+
+        /// Handle everything the other core has sent, returning whether one
+        /// of them was the reply we were waiting for.
+        fn run_us(&mut self) -> bool {
+            let mut replied = false;
+            while let Some(word) = self.to_us.pop_front() {
+                replied |= self.us.receive(word);
+            }
+            replied
+        }
+
+        fn park(&mut self) {
+            let word = self.us.park();
+            self.send(word);
+            self.run_them();
+            assert!(self.run_us());
+            assert_eq!(self.us.state(), Initiator::Parked);
+            assert_eq!(self.them, Parkee::Parked);
+        }
+
+        fn release(&mut self) {
+            let word = self.us.release();
+            self.send(word);
+            self.run_them();
+            assert!(self.run_us());
+            assert_eq!(self.us.state(), Initiator::Idle);
+            assert_eq!(self.them, Parkee::Running);
+        }
+    }
+
+    #[test]
+    fn park_and_release() {
+        let mut cores = Cores::new();
+        for _ in 0..2 {
+            cores.park();
+            // Parking twice is refused.
+            assert_eq!(cores.us.park(), None);
+            cores.release();
+        }
+        assert_eq!(cores.us.stale(), 0);
+    }
+
+    #[test]
+    fn out_of_order_requests_are_refused() {
+        let mut us = Handshake::new();
+        assert_eq!(us.release(), None);
+        assert_eq!(us.park(), Some(LOCKOUT_START));
+        assert_eq!(us.release(), None);
+        assert_eq!(us.park(), None);
+    }
+
+    #[test]
+    fn wrong_replies_are_discarded() {
+        let mut us = Handshake::new();
+        us.park();
+        assert!(!us.receive(LOCKOUT_END));
+        assert!(!us.receive(0x1234_5678));
+        assert_eq!(us.state(), Initiator::Parking);
+        assert!(us.receive(LOCKOUT_START));
+        assert_eq!(us.state(), Initiator::Parked);
+
+        // Nothing is waited for while idle or parked.
+        assert!(!us.receive(LOCKOUT_START));
+        assert!(!Handshake::new().receive(LOCKOUT_START));
+    }
+
+    #[test]
+    fn parkee_only_answers_the_handshake() {
+        assert_eq!(
+            Parkee::Running.receive(0x1234_5678),
+            (Parkee::Running, None)
+        );
+        assert_eq!(
+            Parkee::Running.receive(LOCKOUT_END),
+            (Parkee::Running, None)
+        );
+        assert_eq!(Parkee::Parked.receive(0x1234_5678), (Parkee::Parked, None));
+        assert_eq!(
+            Parkee::Parked.receive(LOCKOUT_START),
+            (Parkee::Parked, None)
+        );
+    }
+
+    #[test]
+    fn timed_out_park_releases_the_other_core() {
+        let mut cores = Cores::new();
+        let word = cores.us.park();
+        cores.send(word);
+
+        // The other core is busy, so we give up before it reads the request.
+        let word = cores.us.give_up();
+        assert_eq!(word, Some(LOCKOUT_END));
+        cores.send(word);
+        assert_eq!(cores.us.state(), Initiator::Idle);
+        assert_eq!(cores.us.stale(), 2);
+
+        // Until it catches up, another park is refused.
+        assert_eq!(cores.us.park(), None);
+
+        // When it does, it parks and is released straight away, rather than
+        // spinning forever.
+        cores.run_them();
+        assert_eq!(cores.them, Parkee::Running);
+        assert_eq!(cores.to_us, [LOCKOUT_START, LOCKOUT_END]);
+
+        // Its echoes are not taken as replies.
+        assert!(!cores.run_us());
+        assert_eq!(cores.us.state(), Initiator::Idle);
+        assert_eq!(cores.us.stale(), 0);
+
+        cores.park();
+        cores.release();
+    }
+
+    #[test]
+    fn late_echo_is_not_a_reply() {
+        let mut cores = Cores::new();
+        let word = cores.us.park();
+        cores.send(word);
+        cores.run_them();
+        let word = cores.us.give_up();
+        cores.send(word);
+
+        // The echo of the abandoned request turns up while the other core is
+        // still to see the release.
+        assert!(!cores.run_us());
+        assert_eq!(cores.us.stale(), 1);
+        assert_eq!(cores.us.park(), None);
+
+        cores.run_them();
+        assert!(!cores.run_us());
+        assert_eq!(cores.us.stale(), 0);
+        cores.park();
+    }
+
+    #[test]
+    fn timed_out_release() {
+        let mut cores = Cores::new();
+        cores.park();
+        let word = cores.us.release();
+        cores.send(word);
+
+        assert_eq!(cores.us.give_up(), None);
+        assert_eq!(cores.us.state(), Initiator::Idle);
+        assert_eq!(cores.us.stale(), 1);
+
+        cores.run_them();
+        assert_eq!(cores.them, Parkee::Running);
+        assert!(!cores.run_us());
+        assert_eq!(cores.us.stale(), 0);
+        cores.park();
+    }
+}
@@ -0,0 +1,312 @@
+//! A [`QmiBus`] which records what the driver does, for running it without
+//! hardware.
+//!
+//! This is built for the crate's own tests, and with the `mock` feature.
+//!
+//! ```ignore
+//! let mut bus = RecordingBus::<64>::new().respond(&read_id_response);
+//! let id = detect_psram(&mut bus)?;
+//! assert!(bus.sent().map(|(_, b)| b).eq([0x66, 0x99, 0x66, 0x99, 0x9F, 0xFF, ...]));
+//! ```
+
+use heapless::{Deque, Vec};
+
+use super::{CommandSet, PsramError, PsramPart, PsramTiming, QmiBus, Width};
+
+/// Maximum number of scripted response bytes.
+const RESPONSE_CAPACITY: usize = 32;
+
+/// One call the driver made on the bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Op {
+    EnableDirect {
+        clkdiv: u8,
+        auto_cs1n: bool,
+    },
+    DisableDirect,
+    Select(bool),
+    WaitIdle,
+    Transfer {
+        width: Width,
+        data: u8,
+    },
+    Send {
+        width: Width,
+        data: u8,
+    },
+    Receive {
+        width: Width,
+    },
+    Delay {
+        ns: u32,
+    },
+    ConfigureM1 {
+        page_size: u32,
+        commands: CommandSet,
+        timing: PsramTiming,
+    },
+    UpdateM1Timing(PsramTiming),
+    MapM1(bool),
+}
+
+/// Records up to `N` bus operations, and plays back scripted responses to
+/// transfers.
+#[derive(Debug, Default)]
+pub struct RecordingBus<const N: usize> {
+    ops: Vec<Op, N>,
+    responses: Deque<u8, RESPONSE_CAPACITY>,
+    idle_waits: usize,
+    timeout_at: Option<usize>,
+}
+
+impl<const N: usize> RecordingBus<N> {
+    /// A bus with no responses scripted.
+    pub fn new() -> Self {
+        RecordingBus {
+            ops: Vec::new(),
+            responses: Deque::new(),
+            idle_waits: 0,
+            timeout_at: None,
+        }
+    }
+
+    /// Queue bytes to be returned by transfers, in order. Once they run out,
+    /// transfers read 0xFF, as from a floating bus.
+    pub fn respond(mut self, bytes: &[u8]) -> Self {
+        for &byte in bytes {
+            self.responses
+                .push_back(byte)
+                .expect("too many scripted responses");
+        }
+        self
+    }
+
+    /// Make the `n`th wait for idle (counting from zero), and every one after
+    /// it, time out.
+    pub fn time_out_at(mut self, n: usize) -> Self {
+        self.timeout_at = Some(n);
+        self
+    }
+
+    /// Everything recorded so far.
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// The bytes clocked out, by transfers and sends, in order.
+    pub fn sent(&self) -> impl Iterator<Item = (Width, u8)> + '_ {
+        self.ops.iter().filter_map(|op| match *op {
+            Op::Transfer { width, data } | Op::Send { width, data } => Some((width, data)),
+            _ => None,
+        })
+    }
+
+    /// The last M1 timing programmed, by either a full configuration or an
+    /// update.
+    pub fn m1_timing(&self) -> Option<PsramTiming> {
+        self.ops.iter().rev().find_map(|op| match *op {
+            Op::ConfigureM1 { timing, .. } | Op::UpdateM1Timing(timing) => Some(timing),
+            _ => None,
+        })
+    }
+
+    fn record(&mut self, op: Op) {
+        self.ops.push(op).expect("recording buffer full");
+    }
+}
+
+impl<const N: usize> QmiBus for RecordingBus<N> {
+    fn enable_direct(&mut self, clkdiv: u8, auto_cs1n: bool) {
+        self.record(Op::EnableDirect { clkdiv, auto_cs1n });
+    }
+
+    fn disable_direct(&mut self) {
+        self.record(Op::DisableDirect);
+    }
+
+    fn select(&mut self, asserted: bool) {
+        self.record(Op::Select(asserted));
+    }
+
+    fn wait_idle(&mut self) -> Result<(), PsramError> {
+        self.record(Op::WaitIdle);
+        let n = self.idle_waits;
+        self.idle_waits += 1;
+        match self.timeout_at {
+            Some(at) if n >= at => Err(PsramError::BusTimeout),
+            _ => Ok(()),
+        }
+    }
+
+    fn transfer(&mut self, width: Width, data: u8) -> Result<u8, PsramError> {
+        self.record(Op::Transfer { width, data });
+        self.wait_idle()?;
+        Ok(self.responses.pop_front().unwrap_or(0xFF))
+    }
+
+    fn send(&mut self, width: Width, data: u8) -> Result<(), PsramError> {
+        self.wait_idle()?;
+        self.record(Op::Send { width, data });
+        self.wait_idle()
+    }
+
+    fn receive(&mut self, width: Width) -> Result<u8, PsramError> {
+        self.record(Op::Receive { width });
+        self.wait_idle()?;
+        Ok(self.responses.pop_front().unwrap_or(0xFF))
+    }
+
+    fn delay_ns(&mut self, ns: u32) {
+        self.record(Op::Delay { ns });
+    }
+
+    fn configure_m1(&mut self, part: &PsramPart, timing: &PsramTiming) {
+        self.record(Op::ConfigureM1 {
+            page_size: part.page_size,
+            commands: part.commands,
+            timing: *timing,
+        });
+    }
+
+    fn update_m1_timing(&mut self, timing: &PsramTiming) {
+        self.record(Op::UpdateM1Timing(*timing));
+    }
+
+    fn map_m1(&mut self, mapped: bool) {
+        self.record(Op::MapM1(mapped));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psram::{
+        configure_qpi_direct, detect_psram_direct, PsramDevice, APS_COMMANDS, APS_PARAMS, PARTS,
+    };
+
+    // These call the `_direct` halves of the driver functions, as the
+    // critical section taken by the public wrappers needs the hardware
+    // spinlocks.
+
+    /// Read ID response from an APS6404L: four bytes clocked in during the
+    /// command and address, then MF ID, KGD and EID.
+    const APS6404L_ID: [u8; 7] = [0xFF, 0xFF, 0xFF, 0xFF, 0x0D, 0x5D, 0b010 << 5];
+
+    #[test]
+    fn detect_sends_reset_then_read_id() {
+        let mut bus = RecordingBus::<64>::new().respond(&APS6404L_ID);
+        let id = detect_psram_direct(&mut bus).unwrap();
+
+        let mut expected = vec![
+            (Width::Quad, 0x66),
+            (Width::Quad, 0x99),
+            (Width::Single, 0x66),
+            (Width::Single, 0x99),
+            (Width::Single, 0x9F),
+        ];
+        expected.extend([(Width::Single, 0xFF); 11]);
+        assert!(bus.sent().eq(expected));
+
+        // Each reset command gets its own selection, and the part gets tRST
+        // before Read ID.
+        let ops = bus.ops();
+        assert_eq!(
+            ops[..7],
+            [
+                Op::EnableDirect {
+                    clkdiv: 30,
+                    auto_cs1n: false
+                },
+                Op::WaitIdle,
+                Op::Select(true),
+                Op::WaitIdle,
+                Op::Send {
+                    width: Width::Quad,
+                    data: 0x66
+                },
+                Op::WaitIdle,
+                Op::Select(false),
+            ]
+        );
+        let delay = ops.iter().position(|op| *op == Op::Delay { ns: 50 });
+        let read_id = ops.iter().position(|op| {
+            *op == Op::Transfer {
+                width: Width::Single,
+                data: 0x9F,
+            }
+        });
+        assert!(delay.unwrap() < read_id.unwrap());
+        assert_eq!(ops.last(), Some(&Op::DisableDirect));
+
+        assert_eq!(id.raw[..3], [0x0D, 0x5D, 0b010 << 5]);
+        assert_eq!(id.device(), PsramDevice::Known(&PARTS[0]));
+        assert_eq!(id.size(), Some(8 * 1024 * 1024));
+    }
+
+    #[test]
+    fn detect_with_nothing_attached() {
+        let mut bus = RecordingBus::<64>::new();
+        let id = detect_psram_direct(&mut bus).unwrap();
+        assert_eq!(id.raw, [0xFF; 8]);
+        assert_eq!(id.device(), PsramDevice::NotPresent);
+    }
+
+    #[test]
+    fn configure_qpi_sends_enter_qpi_and_programs_m1() {
+        let part = PARTS[0];
+        let timing = PsramTiming::compute(150_000_000, &APS_PARAMS).unwrap();
+        let mut bus = RecordingBus::<16>::new();
+        configure_qpi_direct(&mut bus, &part, &timing).unwrap();
+
+        assert_eq!(
+            bus.ops(),
+            [
+                Op::EnableDirect {
+                    clkdiv: 10,
+                    auto_cs1n: true
+                },
+                Op::WaitIdle,
+                Op::Send {
+                    width: Width::Single,
+                    data: 0x35
+                },
+                Op::WaitIdle,
+                Op::ConfigureM1 {
+                    page_size: 1024,
+                    commands: APS_COMMANDS,
+                    timing: PsramTiming {
+                        clkdiv: 2,
+                        rxdelay: 2,
+                        max_select: 18,
+                        min_deselect: 2,
+                    },
+                },
+                Op::DisableDirect,
+            ]
+        );
+        assert_eq!(bus.m1_timing(), Some(timing));
+    }
+
+    #[test]
+    fn detect_times_out() {
+        // Straight away, and part way through the reset sequence.
+        for at in [0, 3] {
+            let mut bus = RecordingBus::<64>::new().time_out_at(at);
+            assert_eq!(detect_psram_direct(&mut bus), Err(PsramError::BusTimeout));
+            assert_eq!(bus.ops().last(), Some(&Op::DisableDirect));
+            assert!(!bus.sent().any(|(_, data)| data == 0x9F));
+        }
+    }
+
+    #[test]
+    fn configure_qpi_times_out_without_touching_m1() {
+        let timing = PsramTiming::compute(150_000_000, &APS_PARAMS).unwrap();
+        let mut bus = RecordingBus::<16>::new().time_out_at(1);
+        assert_eq!(
+            configure_qpi_direct(&mut bus, &PARTS[0], &timing),
+            Err(PsramError::BusTimeout)
+        );
+        assert_eq!(bus.m1_timing(), None);
+        assert_eq!(bus.ops().last(), Some(&Op::DisableDirect));
+    }
+}
@@ -0,0 +1,240 @@
+//! Putting the PSRAM into its low-power state and waking it again.
+//!
+//! While the PSRAM is asleep the M1 window is unmapped from the translated
+//! aliases, so a stray access through the cached or uncached alias is a bus
+//! fault rather than a read of whatever the floating data lines return. M1 is
+//! also made read-only, so a write through any alias is a bus fault.
+//!
+//! A read through the untranslated alias,
+//! [`PsramAlias::UncachedUntranslated`](super::PsramAlias::UncachedUntranslated),
+//! is not caught: it asserts CS, which wakes the part without the driver
+//! knowing. Regions borrow the [`Psram`], so none can be in use while it
+//! sleeps, but raw pointers into that alias must not be read.
+//!
+//! Driver calls which would touch the PSRAM fail with
+//! [`PsramError::Asleep`].
+
+use rp235x_hal::gpio::{PinId, PullType};
+
+use super::{Psram, PsramError, PsramPart, QmiBus, SleepMode, Width, COMMAND_CLKDIV};
+
+impl<I: PinId, P: PullType> Psram<I, P> {
+    /// Put the PSRAM into its low-power state.
+    ///
+    /// The XIP cache is flushed first, so nothing dirty is left to write
+    /// back. If the part's state does not retain data (see
+    /// [`SleepMode::retains_data`]) the contents are lost. Nothing may be
+    /// using the PSRAM, including the other core.
+    pub fn sleep(&mut self) -> Result<(), PsramError> {
+        if self.asleep {
+            return Ok(());
+        }
+        // Copy the table entry out of flash before entering direct mode.
+        let mode = sleep_mode(self.info.part)?;
+        self.parked(|psram| {
+            psram.flush_cache();
+            enter_sleep(&mut psram.qmi, &mode)?;
+            psram.xip.ctrl().modify(|_, w| w.writable_m1().clear_bit());
+            Ok(())
+        })?;
+        self.asleep = true;
+        Ok(())
+    }
+
+    /// Wake the PSRAM, waiting until it can be accessed again.
+    ///
+    /// After a state which does not retain data, the part is reset and set
+    /// up again.
+    pub fn wake(&mut self) -> Result<(), PsramError> {
+        if !self.asleep {
+            return Ok(());
+        }
+        let mode = sleep_mode(self.info.part)?;
+        self.parked(|psram| {
+            exit_sleep(&mut psram.qmi, &mode)?;
+            psram.xip.ctrl().modify(|_, w| w.writable_m1().set_bit());
+            Ok(())
+        })?;
+        self.asleep = false;
+        if !mode.retains_data() {
+            self.reset()?;
+        }
+        Ok(())
+    }
+
+    /// Is the PSRAM asleep?
+    pub fn is_asleep(&self) -> bool {
+        self.asleep
+    }
+
+    /// Fail with [`PsramError::Asleep`] if the PSRAM is asleep.
+    pub(super) fn check_awake(&self) -> Result<(), PsramError> {
+        if self.asleep {
+            Err(PsramError::Asleep)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The low-power state of `part`, if we support one.
+fn sleep_mode(part: &PsramPart) -> Result<SleepMode, PsramError> {
+    part.sleep.ok_or(PsramError::SleepUnsupported)
+}
+
+/// Send the sleep command, then unmap M1 from the translated aliases. The
+/// PSRAM must be in QPI mode.
+pub fn enter_sleep<B: QmiBus>(bus: &mut B, mode: &SleepMode) -> Result<(), PsramError> {
+    critical_section::with(|_cs| enter_sleep_direct(bus, mode))
+}
+
+#[link_section = ".ram_func"]
+#[inline(never)]
+fn enter_sleep_direct<B: QmiBus>(bus: &mut B, mode: &SleepMode) -> Result<(), PsramError> {
+    bus.enable_direct(COMMAND_CLKDIV, false);
+
+    let result = send_sleep(bus, mode);
+    if result.is_ok() {
+        bus.map_m1(false);
+    }
+
+    bus.disable_direct();
+    result
+}
+
+#[inline(always)]
+fn send_sleep<B: QmiBus>(bus: &mut B, mode: &SleepMode) -> Result<(), PsramError> {
+    bus.wait_idle()?;
+    bus.select(true);
+    bus.send(Width::Quad, mode.command)?;
+    bus.select(false);
+    bus.delay_ns(mode.enter_ns);
+    Ok(())
+}
+
+/// Pulse CS to wake the PSRAM, wait for it to be ready, then map M1 again.
+pub fn exit_sleep<B: QmiBus>(bus: &mut B, mode: &SleepMode) -> Result<(), PsramError> {
+    critical_section::with(|_cs| exit_sleep_direct(bus, mode))
+}
+
+#[link_section = ".ram_func"]
+#[inline(never)]
+fn exit_sleep_direct<B: QmiBus>(bus: &mut B, mode: &SleepMode) -> Result<(), PsramError> {
+    bus.enable_direct(COMMAND_CLKDIV, false);
+
+    let result = bus.wait_idle();
+    if result.is_ok() {
+        bus.select(true);
+        bus.delay_ns(mode.wake_pulse_ns);
+        bus.select(false);
+        bus.delay_ns(mode.wake_ns);
+        bus.map_m1(true);
+    }
+
+    bus.disable_direct();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psram::mock::{Op, RecordingBus};
+    use crate::psram::sim::{Mode, PsramModel};
+    use crate::psram::{configure_qpi_direct, PsramTiming, APS_HALF_SLEEP, PARTS};
+
+    // As elsewhere, these call the `_direct` halves, which don't need the
+    // hardware spinlocks.
+
+    /// An APS6404L in QPI mode, as `psram_init` leaves it.
+    fn chip() -> PsramModel<1024> {
+        let mut chip = PsramModel::aps6404l();
+        let timing = PsramTiming::compute(150_000_000, &PARTS[0].params).unwrap();
+        configure_qpi_direct(&mut chip, &PARTS[0], &timing).unwrap();
+        chip
+    }
+
+    #[test]
+    fn sleep_sends_half_sleep_in_quad_then_unmaps() {
+        let mut bus = RecordingBus::<16>::new();
+        enter_sleep_direct(&mut bus, &APS_HALF_SLEEP).unwrap();
+        assert_eq!(
+            bus.ops(),
+            [
+                Op::EnableDirect {
+                    clkdiv: COMMAND_CLKDIV,
+                    auto_cs1n: false,
+                },
+                Op::WaitIdle,
+                Op::Select(true),
+                Op::WaitIdle,
+                Op::Send {
+                    width: Width::Quad,
+                    data: 0xC0,
+                },
+                Op::WaitIdle,
+                Op::Select(false),
+                Op::Delay { ns: 150 },
+                Op::MapM1(false),
+                Op::DisableDirect,
+            ]
+        );
+    }
+
+    #[test]
+    fn wake_pulses_cs_and_waits_before_mapping() {
+        let mut bus = RecordingBus::<16>::new();
+        exit_sleep_direct(&mut bus, &APS_HALF_SLEEP).unwrap();
+        assert_eq!(
+            bus.ops(),
+            [
+                Op::EnableDirect {
+                    clkdiv: COMMAND_CLKDIV,
+                    auto_cs1n: false,
+                },
+                Op::WaitIdle,
+                Op::Select(true),
+                Op::Delay { ns: 60 },
+                Op::Select(false),
+                Op::Delay { ns: 150_000 },
+                Op::MapM1(true),
+                Op::DisableDirect,
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_sleep_leaves_m1_mapped() {
+        let mut bus = RecordingBus::<16>::new().time_out_at(0);
+        assert_eq!(
+            enter_sleep_direct(&mut bus, &APS_HALF_SLEEP),
+            Err(PsramError::BusTimeout)
+        );
+        assert!(!bus.ops().contains(&Op::MapM1(false)));
+    }
+
+    #[test]
+    fn sleep_and_wake_keep_the_contents() {
+        let mut chip = chip();
+        chip.xip_write(0x20, &[1, 2, 3, 4]);
+
+        enter_sleep_direct(&mut chip, &APS_HALF_SLEEP).unwrap();
+        assert!(chip.is_asleep());
+        assert!(!chip.is_m1_mapped());
+
+        exit_sleep_direct(&mut chip, &APS_HALF_SLEEP).unwrap();
+        assert!(!chip.is_asleep());
+        assert!(chip.is_m1_mapped());
+        assert_eq!(chip.mode(), Mode::Qpi);
+
+        let mut buf = [0; 4];
+        chip.xip_read(0x20, &mut buf);
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn parts_without_sleep_are_unsupported() {
+        assert_eq!(sleep_mode(&PARTS[0]), Ok(APS_HALF_SLEEP));
+        let issi = PARTS.iter().find(|part| part.name == "IS66WVS4M8").unwrap();
+        assert_eq!(sleep_mode(issi), Err(PsramError::SleepUnsupported));
+    }
+}
@@ -0,0 +1,144 @@
+//! The PSRAM's aliases in the XIP address space, and regions within them.
+//!
+//! The M1 window appears several times in the XIP address space. Accesses
+//! through [`PsramAlias::Cached`] go through the 16 KiB XIP cache, which is
+//! what you want for most data. Large streaming buffers (audio, capture) that
+//! are touched once would just evict everything else, so they are better
+//! accessed through one of the uncached aliases.
+
+use core::marker::PhantomData;
+use core::ops::Range;
+
+use rp235x_hal::gpio::{PinId, PullType};
+
+use super::{Psram, PsramError, PSRAM_BASE, PSRAM_NOCACHE_BASE};
+
+/// Base address of the uncached, untranslated alias of [`PSRAM_BASE`].
+const PSRAM_NOCACHE_NOTRANSLATE_BASE: usize = 0x1D00_0000;
+
+/// One of the aliases through which the PSRAM can be accessed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum PsramAlias {
+    /// Cached, allocating on miss.
+    Cached,
+    /// Bypasses the cache, and does not allocate.
+    Uncached,
+    /// Bypasses the cache and the address translation set up in XIP_CTRL.
+    UncachedUntranslated,
+}
+
+impl PsramAlias {
+    /// Address of the start of the PSRAM through this alias.
+    pub const fn base(self) -> usize {
+        match self {
+            PsramAlias::Cached => PSRAM_BASE,
+            PsramAlias::Uncached => PSRAM_NOCACHE_BASE,
+            PsramAlias::UncachedUntranslated => PSRAM_NOCACHE_NOTRANSLATE_BASE,
+        }
+    }
+}
+
+/// A range of the PSRAM, accessed through a particular alias.
+///
+/// A region stands for exclusive use of its bytes, so it can hand out
+/// references to them. It borrows the [`Psram`] it came from, so the PSRAM
+/// cannot be freed, reset or put to sleep while the region is in use.
+#[derive(Debug, PartialEq, Eq, defmt::Format)]
+pub struct PsramRegion<'p> {
+    alias: PsramAlias,
+    offset: u32,
+    len: u32,
+    psram: PhantomData<&'p ()>,
+}
+
+// Safety: a region is exclusive use of some PSRAM, which is visible to
+// every core.
+unsafe impl Send for PsramRegion<'_> {}
+
+impl PsramRegion<'_> {
+    /// The alias the region was created from.
+    pub fn alias(&self) -> PsramAlias {
+        self.alias
+    }
+
+    /// Byte offset of the region from the start of the PSRAM.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Length of the region in bytes.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Is the region zero length?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Address range of the region, through its alias.
+    pub fn addresses(&self) -> Range<usize> {
+        let start = self.alias.base() + self.offset as usize;
+        start..start + self.len as usize
+    }
+
+    /// Pointer to the start of the region, through its alias.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.addresses().start as *const u8
+    }
+
+    /// Mutable pointer to the start of the region, through its alias.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.addresses().start as *mut u8
+    }
+
+    /// The region's bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        // Safety: the region is exclusively ours, and mapped.
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    /// The region's bytes, mutably.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: as above.
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    }
+}
+
+impl<I: PinId, P: PullType> Psram<I, P> {
+    /// Take the bytes at `range` (offsets into the PSRAM) as a region
+    /// accessed through `alias`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may use these bytes, through any alias, for as long as
+    /// the region exists. In particular they must not be part of a heap.
+    pub unsafe fn region(
+        &self,
+        alias: PsramAlias,
+        range: Range<u32>,
+    ) -> Result<PsramRegion<'_>, PsramError> {
+        if range.start > range.end || range.end > self.size() {
+            return Err(PsramError::OutOfBounds);
+        }
+        Ok(PsramRegion {
+            alias,
+            offset: range.start,
+            len: range.end - range.start,
+            psram: PhantomData,
+        })
+    }
+
+    /// Move a region to a different alias.
+    ///
+    /// When leaving the cached alias the region's cache lines are written
+    /// back and dropped, so accesses through the new alias see the latest
+    /// data and stale lines cannot be written back over it later.
+    pub fn realias<'p>(&self, region: PsramRegion<'p>, alias: PsramAlias) -> PsramRegion<'p> {
+        if region.alias == PsramAlias::Cached && alias != PsramAlias::Cached {
+            // The region was checked against our size when it was created.
+            let _ = self.clean_invalidate_range(region.addresses());
+        }
+        PsramRegion { alias, ..region }
+    }
+}
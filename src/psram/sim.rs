@@ -0,0 +1,557 @@
+//! A behavioural model of a QSPI PSRAM, driven through [`QmiBus`].
+//!
+//! Where `mock::RecordingBus` only plays back scripted bytes, [`PsramModel`]
+//! implements the command state machine of an APS6404L-style part: SPI/QPI
+//! mode switching, Read ID, quad read with wait cycles, quad write, reset and
+//! half sleep. That lets [`detect_psram`](super::detect_psram) and
+//! [`configure_qpi`](super::configure_qpi) run end to end off target,
+//! including against a misbehaving chip:
+//!
+//! ```ignore
+//! let mut chip = PsramModel::<1024>::aps6404l().in_qpi_mode();
+//! let id = detect_psram(&mut chip)?;
+//! configure_qpi(&mut chip, id.device().known()?, &timing)?;
+//! chip.xip_write(0x10, &[1, 2, 3]);
+//! ```
+//!
+//! This is built for the crate's own tests, and with the `sim` feature.
+//!
+//! Only whole bytes are modelled. A byte clocked at the wrong width for the
+//! chip's current mode garbles the rest of that command, which is how a real
+//! part treats a QPI command sent while it is in SPI mode and vice versa.
+
+use super::{
+    CommandSet, PsramError, PsramPart, PsramTiming, QmiBus, Width, APS_COMMANDS, APS_HALF_SLEEP,
+};
+
+const RESET_ENABLE: u8 = APS_COMMANDS.reset_enable;
+const RESET: u8 = APS_COMMANDS.reset;
+const ENTER_QPI: u8 = APS_COMMANDS.enter_qpi;
+const EXIT_QPI: u8 = APS_COMMANDS.exit_qpi;
+const READ_ID: u8 = APS_COMMANDS.read_id;
+const QUAD_READ: u8 = APS_COMMANDS.quad_read;
+const QUAD_WRITE: u8 = APS_COMMANDS.quad_write;
+const HALF_SLEEP: u8 = APS_HALF_SLEEP.command;
+
+/// Quad read wait cycles, as whole bytes at quad width.
+const READ_WAIT_BYTES: u8 = 3;
+
+/// The interface mode of the modelled chip.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Mode {
+    Spi,
+    Qpi,
+}
+
+/// A hardware fault to inject.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Fault {
+    /// The data lines are stuck high: the chip only ever sees 0xFF, and the
+    /// host only ever reads 0xFF.
+    StuckHigh,
+    /// The data lines are stuck low.
+    StuckLow,
+    /// The QMI never leaves BUSY, so every wait times out.
+    StuckBusy,
+}
+
+/// What M1 was last programmed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct M1Config {
+    pub page_size: u32,
+    pub commands: CommandSet,
+    pub timing: PsramTiming,
+}
+
+/// Where the chip is within the current command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// CS is deasserted.
+    Deselected,
+    /// Waiting for the command byte.
+    Command,
+    /// Receiving the 24-bit address for `command`.
+    Address {
+        command: u8,
+        remaining: u8,
+        addr: u32,
+    },
+    /// Waiting out the read latency.
+    Wait {
+        remaining: u8,
+        addr: u32,
+    },
+    /// Returning ID bytes.
+    ReadId {
+        index: usize,
+    },
+    ReadData {
+        addr: u32,
+    },
+    WriteData {
+        addr: u32,
+    },
+    /// A single-byte command, which takes effect when CS is deasserted.
+    Complete {
+        command: u8,
+    },
+    /// Something unexpected was clocked in; ignore the rest of the command.
+    Garbled,
+}
+
+/// A QSPI PSRAM with `M` bytes of backing store. Addresses beyond that alias.
+#[derive(Debug)]
+pub struct PsramModel<const M: usize> {
+    id: [u8; 3],
+    page_size: u32,
+    memory: [u8; M],
+    mode: Mode,
+    phase: Phase,
+    reset_enabled: bool,
+    resets: u32,
+    fault: Option<Fault>,
+    direct: bool,
+    auto_cs1n: bool,
+    m1: Option<M1Config>,
+    m1_mapped: bool,
+    asleep: bool,
+}
+
+impl<const M: usize> PsramModel<M> {
+    /// A chip which answers Read ID with the given MF ID, KGD and EID, and
+    /// has the given page size.
+    pub fn new(manufacturer: u8, kgd: u8, eid: u8, page_size: u32) -> Self {
+        PsramModel {
+            id: [manufacturer, kgd, eid],
+            page_size,
+            memory: [0; M],
+            mode: Mode::Spi,
+            phase: Phase::Deselected,
+            reset_enabled: false,
+            resets: 0,
+            fault: None,
+            direct: false,
+            auto_cs1n: false,
+            m1: None,
+            m1_mapped: true,
+            asleep: false,
+        }
+    }
+
+    /// An AP Memory APS6404L (8 MiB).
+    pub fn aps6404l() -> Self {
+        Self::new(0x0D, 0x5D, 0b010 << 5, 1024)
+    }
+
+    /// An ISSI IS66WVS4M8 (4 MiB).
+    pub fn is66wvs4m8() -> Self {
+        Self::new(0x9D, 0x5D, 0b001 << 5, 1024)
+    }
+
+    /// Start in QPI mode, as a chip left configured by a previous boot would.
+    pub fn in_qpi_mode(mut self) -> Self {
+        self.mode = Mode::Qpi;
+        self
+    }
+
+    /// Inject a fault.
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    /// The chip's current interface mode.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// How many times the chip has been reset.
+    pub fn resets(&self) -> u32 {
+        self.resets
+    }
+
+    /// The backing store.
+    pub fn memory(&self) -> &[u8; M] {
+        &self.memory
+    }
+
+    /// How M1 was last configured.
+    pub fn m1(&self) -> Option<M1Config> {
+        self.m1
+    }
+
+    /// Is the chip in half sleep?
+    pub fn is_asleep(&self) -> bool {
+        self.asleep
+    }
+
+    /// Is M1 mapped into the translated aliases?
+    pub fn is_m1_mapped(&self) -> bool {
+        self.m1_mapped
+    }
+
+    /// Read through the M1 window, as XIP would with the configured read
+    /// command. If the chip is not in QPI mode it will not answer, and `buf`
+    /// reads back as 0xFF.
+    ///
+    /// Panics if M1 is unmapped, where the hardware would bus fault.
+    pub fn xip_read(&mut self, addr: u32, buf: &mut [u8]) {
+        let m1 = self.m1.expect("M1 not configured");
+        assert!(self.m1_mapped, "M1 access while unmapped");
+        self.begin();
+        self.clock_address(m1.commands.quad_read, addr);
+        for _ in 0..READ_WAIT_BYTES {
+            self.clock(Width::Quad, 0xFF);
+        }
+        for byte in buf.iter_mut() {
+            *byte = self.clock(Width::Quad, 0xFF);
+        }
+        self.end();
+    }
+
+    /// Write through the M1 window, as XIP would with the configured write
+    /// command.
+    ///
+    /// Panics if M1 is unmapped, where the hardware would bus fault.
+    pub fn xip_write(&mut self, addr: u32, data: &[u8]) {
+        let m1 = self.m1.expect("M1 not configured");
+        assert!(self.m1_mapped, "M1 access while unmapped");
+        self.begin();
+        self.clock_address(m1.commands.quad_write, addr);
+        for &byte in data {
+            self.clock(Width::Quad, byte);
+        }
+        self.end();
+    }
+
+    fn clock_address(&mut self, command: u8, addr: u32) {
+        self.clock(Width::Quad, command);
+        for shift in [16, 8, 0] {
+            self.clock(Width::Quad, (addr >> shift) as u8);
+        }
+    }
+
+    /// CS asserted. A sleeping chip takes this as the signal to wake, and
+    /// ignores the rest of the selection.
+    fn begin(&mut self) {
+        if self.asleep {
+            self.asleep = false;
+            self.phase = Phase::Garbled;
+        } else {
+            self.phase = Phase::Command;
+        }
+    }
+
+    /// CS deasserted: act on any single-byte command.
+    fn end(&mut self) {
+        let phase = core::mem::replace(&mut self.phase, Phase::Deselected);
+        let Phase::Complete { command } = phase else {
+            // Anything else cancels a pending reset.
+            if phase != Phase::Deselected {
+                self.reset_enabled = false;
+            }
+            return;
+        };
+        let reset_enabled = core::mem::take(&mut self.reset_enabled);
+        match (command, self.mode) {
+            (RESET_ENABLE, _) => self.reset_enabled = true,
+            (RESET, _) if reset_enabled => {
+                self.mode = Mode::Spi;
+                self.resets += 1;
+            }
+            (ENTER_QPI, Mode::Spi) => self.mode = Mode::Qpi,
+            (EXIT_QPI, Mode::Qpi) => self.mode = Mode::Spi,
+            (HALF_SLEEP, _) => self.asleep = true,
+            _ => {}
+        }
+    }
+
+    /// Clock one byte in at `width`, and return what the chip drives back.
+    fn clock(&mut self, width: Width, data: u8) -> u8 {
+        let data = match self.fault {
+            Some(Fault::StuckHigh) => 0xFF,
+            Some(Fault::StuckLow) => 0x00,
+            _ => data,
+        };
+        let native = match self.mode {
+            Mode::Spi => Width::Single,
+            Mode::Qpi => Width::Quad,
+        };
+        if width != native && self.phase != Phase::Deselected {
+            self.phase = Phase::Garbled;
+        }
+
+        let (next, out) = match self.phase {
+            Phase::Deselected | Phase::Garbled => (self.phase, None),
+            Phase::Command => (self.decode(data), None),
+            Phase::Address {
+                command,
+                remaining,
+                addr,
+            } => {
+                let addr = (addr << 8) | u32::from(data);
+                let next = match (remaining, command) {
+                    (1, READ_ID) => Phase::ReadId { index: 0 },
+                    (1, QUAD_READ) => Phase::Wait {
+                        remaining: READ_WAIT_BYTES,
+                        addr,
+                    },
+                    (1, _) => Phase::WriteData { addr },
+                    _ => Phase::Address {
+                        command,
+                        remaining: remaining - 1,
+                        addr,
+                    },
+                };
+                (next, None)
+            }
+            Phase::Wait { remaining, addr } => {
+                let next = if remaining == 1 {
+                    Phase::ReadData { addr }
+                } else {
+                    Phase::Wait {
+                        remaining: remaining - 1,
+                        addr,
+                    }
+                };
+                (next, None)
+            }
+            Phase::ReadId { index } => {
+                let out = self.id.get(index).copied().unwrap_or(0);
+                (Phase::ReadId { index: index + 1 }, Some(out))
+            }
+            Phase::ReadData { addr } => {
+                let out = self.memory[addr as usize % M];
+                (
+                    Phase::ReadData {
+                        addr: self.next_addr(addr),
+                    },
+                    Some(out),
+                )
+            }
+            Phase::WriteData { addr } => {
+                self.memory[addr as usize % M] = data;
+                (
+                    Phase::WriteData {
+                        addr: self.next_addr(addr),
+                    },
+                    None,
+                )
+            }
+            // A single-byte command followed by anything else is invalid.
+            Phase::Complete { .. } => (Phase::Garbled, None),
+        };
+        self.phase = next;
+
+        match self.fault {
+            Some(Fault::StuckHigh) => 0xFF,
+            Some(Fault::StuckLow) => 0x00,
+            // Nothing drives the bus, so the pull-ups win.
+            _ => out.unwrap_or(0xFF),
+        }
+    }
+
+    /// Work out what a command byte starts, given the current mode.
+    fn decode(&self, command: u8) -> Phase {
+        let address = Phase::Address {
+            command,
+            remaining: 3,
+            addr: 0,
+        };
+        match (command, self.mode) {
+            (RESET_ENABLE | RESET | HALF_SLEEP, _) => Phase::Complete { command },
+            (ENTER_QPI, Mode::Spi) | (EXIT_QPI, Mode::Qpi) => Phase::Complete { command },
+            (READ_ID, Mode::Spi) => address,
+            (QUAD_READ | QUAD_WRITE, Mode::Qpi) => address,
+            _ => Phase::Garbled,
+        }
+    }
+
+    /// Bursts wrap within a page.
+    fn next_addr(&self, addr: u32) -> u32 {
+        let mask = self.page_size - 1;
+        (addr & !mask) | (addr.wrapping_add(1) & mask)
+    }
+
+    fn fail_if_stuck(&self) -> Result<(), PsramError> {
+        match self.fault {
+            Some(Fault::StuckBusy) => Err(PsramError::BusTimeout),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<const M: usize> QmiBus for PsramModel<M> {
+    fn enable_direct(&mut self, _clkdiv: u8, auto_cs1n: bool) {
+        self.direct = true;
+        self.auto_cs1n = auto_cs1n;
+    }
+
+    fn disable_direct(&mut self) {
+        self.end();
+        self.direct = false;
+        self.auto_cs1n = false;
+    }
+
+    fn select(&mut self, asserted: bool) {
+        if asserted {
+            self.begin();
+        } else {
+            self.end();
+        }
+    }
+
+    fn wait_idle(&mut self) -> Result<(), PsramError> {
+        self.fail_if_stuck()
+    }
+
+    fn transfer(&mut self, width: Width, data: u8) -> Result<u8, PsramError> {
+        assert!(self.direct, "transfer outside direct mode");
+        if self.auto_cs1n {
+            self.begin();
+        }
+        let out = self.clock(width, data);
+        if self.auto_cs1n {
+            self.end();
+        }
+        self.fail_if_stuck()?;
+        Ok(out)
+    }
+
+    fn send(&mut self, width: Width, data: u8) -> Result<(), PsramError> {
+        self.fail_if_stuck()?;
+        self.transfer(width, data).map(drop)
+    }
+
+    fn receive(&mut self, width: Width) -> Result<u8, PsramError> {
+        self.transfer(width, 0xFF)
+    }
+
+    fn delay_ns(&mut self, _ns: u32) {}
+
+    fn configure_m1(&mut self, part: &PsramPart, timing: &PsramTiming) {
+        self.m1 = Some(M1Config {
+            page_size: part.page_size,
+            commands: part.commands,
+            timing: *timing,
+        });
+    }
+
+    fn update_m1_timing(&mut self, timing: &PsramTiming) {
+        if let Some(m1) = self.m1.as_mut() {
+            m1.timing = *timing;
+        }
+    }
+
+    fn map_m1(&mut self, mapped: bool) {
+        self.m1_mapped = mapped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psram::{configure_qpi_direct, detect_psram_direct, PsramPart, APS_PARAMS, PARTS};
+
+    // As in the mock tests, these call the `_direct` halves of the driver
+    // functions, which don't need the hardware spinlocks.
+
+    /// Detect the chip and look it up, as `psram_init` does.
+    fn identify<const M: usize>(
+        chip: &mut PsramModel<M>,
+    ) -> Result<&'static PsramPart, PsramError> {
+        detect_psram_direct(chip)?.device().known()
+    }
+
+    #[test]
+    fn detects_aps6404l_left_in_qpi_mode() {
+        let mut chip = PsramModel::<1024>::aps6404l().in_qpi_mode();
+        let part = identify(&mut chip).unwrap();
+        assert_eq!(part.name, "APS6404L / LY68L6400 / ESP-PSRAM64H");
+        assert_eq!(part.size, 8 * 1024 * 1024);
+        // The QPI reset took it back to SPI, and the SPI one reset it again.
+        assert_eq!(chip.mode(), Mode::Spi);
+        assert_eq!(chip.resets(), 2);
+    }
+
+    #[test]
+    fn detects_aps6404l_in_spi_mode() {
+        let mut chip = PsramModel::<1024>::aps6404l();
+        let part = identify(&mut chip).unwrap();
+        assert_eq!(part.size, 8 * 1024 * 1024);
+        // The QPI reset is garbage to a chip in SPI mode.
+        assert_eq!(chip.resets(), 1);
+    }
+
+    #[test]
+    fn detects_is66wvs4m8() {
+        let mut chip = PsramModel::<1024>::is66wvs4m8();
+        let part = identify(&mut chip).unwrap();
+        assert_eq!(part.name, "IS66WVS4M8");
+        assert_eq!(part.size, 4 * 1024 * 1024);
+        assert_eq!(chip.mode(), Mode::Spi);
+    }
+
+    #[test]
+    fn stuck_data_lines_are_not_detected() {
+        for fault in [Fault::StuckHigh, Fault::StuckLow] {
+            let mut chip = PsramModel::<1024>::aps6404l().with_fault(fault);
+            assert_eq!(identify(&mut chip), Err(PsramError::NotDetected));
+            assert_eq!(chip.resets(), 0);
+        }
+    }
+
+    #[test]
+    fn stuck_busy_times_out() {
+        let mut chip = PsramModel::<1024>::aps6404l().with_fault(Fault::StuckBusy);
+        assert_eq!(identify(&mut chip), Err(PsramError::BusTimeout));
+
+        let timing = PsramTiming::compute(150_000_000, &APS_PARAMS).unwrap();
+        let part = PARTS[0];
+        assert_eq!(
+            configure_qpi_direct(&mut chip, &part, &timing),
+            Err(PsramError::BusTimeout)
+        );
+        assert_eq!(chip.mode(), Mode::Spi);
+        assert_eq!(chip.m1(), None);
+    }
+
+    #[test]
+    fn configure_qpi_then_xip() {
+        let mut chip = PsramModel::<1024>::aps6404l().in_qpi_mode();
+        let part = *identify(&mut chip).unwrap();
+        let timing = PsramTiming::compute(150_000_000, &APS_PARAMS).unwrap();
+        configure_qpi_direct(&mut chip, &part, &timing).unwrap();
+
+        assert_eq!(chip.mode(), Mode::Qpi);
+        assert_eq!(
+            chip.m1(),
+            Some(M1Config {
+                page_size: 1024,
+                commands: APS_COMMANDS,
+                timing,
+            })
+        );
+
+        chip.xip_write(0x10, &[1, 2, 3]);
+        let mut buf = [0; 4];
+        chip.xip_read(0x0F, &mut buf);
+        assert_eq!(buf, [0, 1, 2, 3]);
+        assert_eq!(chip.memory()[0x10..0x13], [1, 2, 3]);
+    }
+
+    #[test]
+    fn xip_without_qpi_reads_nothing() {
+        let mut chip = PsramModel::<1024>::aps6404l();
+        let part = *identify(&mut chip).unwrap();
+        let timing = PsramTiming::compute(150_000_000, &APS_PARAMS).unwrap();
+        // Program M1 but never send Enter QPI.
+        chip.configure_m1(&part, &timing);
+
+        chip.xip_write(0x10, &[1, 2, 3]);
+        let mut buf = [0; 3];
+        chip.xip_read(0x10, &mut buf);
+        assert_eq!(buf, [0xFF; 3]);
+        assert_eq!(chip.memory()[0x10..0x13], [0; 3]);
+    }
+}
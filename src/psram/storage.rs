@@ -0,0 +1,230 @@
+//! The PSRAM as an `embedded-storage` device.
+//!
+//! This lets filesystem and key-value crates written for flash use the PSRAM
+//! as a RAM disk. Its contents are lost at power off, so it suits caches and
+//! scratch data rather than anything that must persist.
+//!
+//! Erasing only sets the bytes to 0xFF, so it is as cheap as a write, and any
+//! byte can be rewritten any number of times.
+
+use core::ops::Range;
+
+use embedded_storage::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use embedded_storage::{ReadStorage, Storage};
+
+use super::{PsramError, PsramRegion};
+
+/// Value of an erased byte, as on NOR flash.
+const ERASED: u8 = 0xFF;
+
+/// A [`PsramRegion`] used as storage, with offsets relative to its start.
+///
+/// To use the whole PSRAM through the cache:
+///
+/// ```ignore
+/// let region = unsafe { psram.region(PsramAlias::Cached, 0..psram.size()) }?;
+/// let storage = PsramStorage::new(region);
+/// ```
+#[derive(Debug, defmt::Format)]
+pub struct PsramStorage<'p> {
+    region: PsramRegion<'p>,
+}
+
+impl<'p> PsramStorage<'p> {
+    /// Erase block size reported to NOR flash users. Erasing costs the same
+    /// at any size, so this is just the common flash sector size, which is
+    /// what most filesystems expect.
+    pub const ERASE_SIZE: usize = 4096;
+
+    /// Use `region` as storage.
+    pub fn new(region: PsramRegion<'p>) -> Self {
+        PsramStorage { region }
+    }
+
+    /// Give the region back.
+    pub fn free(self) -> PsramRegion<'p> {
+        self.region
+    }
+}
+
+/// The bytes `offset..offset + len` of `storage`, if they are inside it.
+fn range(storage: &[u8], offset: u32, len: usize) -> Result<Range<usize>, PsramError> {
+    let start = offset as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= storage.len() => Ok(start..end),
+        _ => Err(PsramError::OutOfBounds),
+    }
+}
+
+fn read(storage: &[u8], offset: u32, bytes: &mut [u8]) -> Result<(), PsramError> {
+    let range = range(storage, offset, bytes.len())?;
+    bytes.copy_from_slice(&storage[range]);
+    Ok(())
+}
+
+fn write(storage: &mut [u8], offset: u32, bytes: &[u8]) -> Result<(), PsramError> {
+    let range = range(storage, offset, bytes.len())?;
+    storage[range].copy_from_slice(bytes);
+    Ok(())
+}
+
+/// Erase `from..to`, which must be whole erase blocks.
+fn erase(storage: &mut [u8], from: u32, to: u32) -> Result<(), PsramError> {
+    if from > to {
+        return Err(PsramError::OutOfBounds);
+    }
+    let range = range(storage, from, (to - from) as usize)?;
+    let block = PsramStorage::ERASE_SIZE;
+    if range.start % block != 0 || range.end % block != 0 {
+        return Err(PsramError::NotAligned);
+    }
+    storage[range].fill(ERASED);
+    Ok(())
+}
+
+impl NorFlashError for PsramError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            PsramError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            PsramError::NotAligned => NorFlashErrorKind::NotAligned,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl ReadStorage for PsramStorage<'_> {
+    type Error = PsramError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PsramError> {
+        read(self.region.as_slice(), offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.region.len()
+    }
+}
+
+impl Storage for PsramStorage<'_> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PsramError> {
+        write(self.region.as_mut_slice(), offset, bytes)
+    }
+}
+
+impl ErrorType for PsramStorage<'_> {
+    type Error = PsramError;
+}
+
+impl ReadNorFlash for PsramStorage<'_> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PsramError> {
+        ReadStorage::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.region.len()
+    }
+}
+
+impl NorFlash for PsramStorage<'_> {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = PsramStorage::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), PsramError> {
+        erase(self.region.as_mut_slice(), from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PsramError> {
+        Storage::write(self, offset, bytes)
+    }
+}
+
+impl MultiwriteNorFlash for PsramStorage<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A region can only be backed by the PSRAM itself, so these test the
+    // checks on a plain buffer standing in for one.
+
+    const BLOCK: u32 = PsramStorage::ERASE_SIZE as u32;
+
+    #[test]
+    fn erase_fills_with_erased_bytes() {
+        let mut storage = vec![0x55; 3 * BLOCK as usize];
+        erase(&mut storage, BLOCK, 2 * BLOCK).unwrap();
+        assert!(storage[..BLOCK as usize].iter().all(|&b| b == 0x55));
+        assert!(storage[BLOCK as usize..2 * BLOCK as usize]
+            .iter()
+            .all(|&b| b == ERASED));
+        assert!(storage[2 * BLOCK as usize..].iter().all(|&b| b == 0x55));
+
+        // Erasing nothing is fine.
+        erase(&mut storage, 0, 0).unwrap();
+        assert_eq!(storage[0], 0x55);
+    }
+
+    #[test]
+    fn unaligned_erase() {
+        let mut storage = vec![0x55; 2 * BLOCK as usize];
+        for (from, to) in [(1, BLOCK), (0, BLOCK - 1), (BLOCK / 2, BLOCK + BLOCK / 2)] {
+            assert_eq!(erase(&mut storage, from, to), Err(PsramError::NotAligned));
+        }
+        assert!(storage.iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn erase_out_of_bounds() {
+        let mut storage = vec![0x55; 2 * BLOCK as usize];
+        assert_eq!(
+            erase(&mut storage, BLOCK, 3 * BLOCK),
+            Err(PsramError::OutOfBounds)
+        );
+        assert_eq!(
+            erase(&mut storage, 2 * BLOCK, 3 * BLOCK),
+            Err(PsramError::OutOfBounds)
+        );
+        assert_eq!(erase(&mut storage, BLOCK, 0), Err(PsramError::OutOfBounds));
+        // Bounds are checked before alignment.
+        assert_eq!(
+            erase(&mut storage, 1, u32::MAX),
+            Err(PsramError::OutOfBounds)
+        );
+        assert!(storage.iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn read_and_write_bounds() {
+        let mut storage = [0; 16];
+        write(&mut storage, 12, &[1, 2, 3, 4]).unwrap();
+        let mut bytes = [0; 4];
+        read(&storage, 12, &mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3, 4]);
+
+        assert_eq!(
+            write(&mut storage, 13, &[0; 4]),
+            Err(PsramError::OutOfBounds)
+        );
+        assert_eq!(read(&storage, 13, &mut bytes), Err(PsramError::OutOfBounds));
+        assert_eq!(
+            read(&storage, u32::MAX, &mut bytes),
+            Err(PsramError::OutOfBounds)
+        );
+        // Empty accesses at the end are fine.
+        read(&storage, 16, &mut []).unwrap();
+        assert_eq!(storage[12..], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn error_kinds() {
+        assert_eq!(
+            PsramError::OutOfBounds.kind(),
+            NorFlashErrorKind::OutOfBounds
+        );
+        assert_eq!(PsramError::NotAligned.kind(), NorFlashErrorKind::NotAligned);
+        assert_eq!(PsramError::Asleep.kind(), NorFlashErrorKind::Other);
+    }
+}
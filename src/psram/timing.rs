@@ -0,0 +1,225 @@
+//! QMI M1 timing calculation.
+//!
+//! This is kept free of register access so the arithmetic can be checked
+//! without hardware.
+
+use core::cmp::Ordering;
+
+use super::PsramError;
+
+/// The timing parameters of a part which constrain the QMI M1 timing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct ChipParams {
+    /// Maximum SCK frequency in QPI mode.
+    pub max_clock_hz: u32,
+    /// Maximum time CS may stay asserted (tCEM), in nanoseconds.
+    pub tcem_ns: u32,
+    /// Minimum time CS must stay deasserted between transfers (tCPH), in
+    /// nanoseconds.
+    pub min_deselect_ns: u32,
+}
+
+/// Field values for the QMI `M1_TIMING` register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct PsramTiming {
+    /// SCK divisor from the system clock.
+    pub clkdiv: u8,
+    /// Delay, in half system clock cycles, before sampling read data.
+    pub rxdelay: u8,
+    /// Maximum CS assertion time, in units of 64 system clock cycles.
+    pub max_select: u8,
+    /// Minimum CS deassertion time, in system clock cycles.
+    pub min_deselect: u8,
+}
+
+/// Largest value that fits in `M1_TIMING.CLKDIV`.
+const CLKDIV_MAX: u32 = 0xFF;
+/// Largest value that fits in `M1_TIMING.RXDELAY`.
+const RXDELAY_MAX: u32 = 0x7;
+/// Largest value that fits in `M1_TIMING.MAX_SELECT`.
+const MAX_SELECT_MAX: u64 = 0x3F;
+/// Largest value that fits in `M1_TIMING.MIN_DESELECT`.
+const MIN_DESELECT_MAX: u64 = 0x1F;
+
+impl PsramTiming {
+    /// Work out the M1 timing for a part when QMI is clocked at
+    /// `sys_clock_hz`.
+    ///
+    /// Returns [`PsramError::ClockOutOfRange`] if any field would not fit in
+    /// its register field, or if the clock is so slow that even the shortest
+    /// `max_select` would exceed tCEM.
+    pub const fn compute(sys_clock_hz: u32, params: &ChipParams) -> Result<Self, PsramError> {
+        let out_of_range = Err(PsramError::ClockOutOfRange {
+            clock_hz: sys_clock_hz,
+        });
+        if sys_clock_hz == 0 || params.max_clock_hz == 0 {
+            return out_of_range;
+        }
+
+        // Using an rxdelay equal to the divisor isn't enough when running the APS6404 close to 133MHz.
+        // So: don't allow running at divisor 1 above 100MHz (because delay of 2 would be too late),
+        // and add an extra 1 to the rxdelay if the divided clock is > 100MHz (i.e. sys clock > 200MHz).
+        let mut divisor = sys_clock_hz.div_ceil(params.max_clock_hz);
+        if divisor == 1 && sys_clock_hz > 100_000_000 {
+            divisor = 2;
+        }
+        let mut rxdelay = divisor;
+        if sys_clock_hz / divisor > 100_000_000 {
+            rxdelay += 1;
+        }
+
+        Self::with_divisor(sys_clock_hz, params, divisor, rxdelay)
+    }
+
+    /// Work out the M1 timing for a part when QMI is clocked at
+    /// `sys_clock_hz`, using the given divisor and rxdelay rather than the
+    /// defaults picked by [`PsramTiming::compute`].
+    ///
+    /// Returns [`PsramError::ClockOutOfRange`] if the divisor would run SCK
+    /// faster than the part allows, or if any field would not fit.
+    pub const fn with_divisor(
+        sys_clock_hz: u32,
+        params: &ChipParams,
+        divisor: u32,
+        rxdelay: u32,
+    ) -> Result<Self, PsramError> {
+        let out_of_range = Err(PsramError::ClockOutOfRange {
+            clock_hz: sys_clock_hz,
+        });
+        if sys_clock_hz == 0 || divisor == 0 || sys_clock_hz.div_ceil(divisor) > params.max_clock_hz
+        {
+            return out_of_range;
+        }
+
+        // - Max select must be <= tCEM.  The value is given in multiples of 64 system clocks.
+        // - Min deselect must be >= tCPH.  The value is given in system clock cycles - ceil(divisor / 2).
+        let clock_period_fs = 1_000_000_000_000_000_u64 / sys_clock_hz as u64;
+        let max_select = (params.tcem_ns as u64 * 1_000_000 / 64) / clock_period_fs;
+        let min_deselect =
+            (params.min_deselect_ns as u64 * 1_000_000 + (clock_period_fs - 1)) / clock_period_fs;
+        let min_deselect = min_deselect.saturating_sub((divisor as u64 + 1) / 2);
+
+        // A max_select of 0 means "no limit", which would break tCEM.
+        if divisor > CLKDIV_MAX
+            || rxdelay > RXDELAY_MAX
+            || max_select == 0
+            || max_select > MAX_SELECT_MAX
+            || min_deselect > MIN_DESELECT_MAX
+        {
+            return out_of_range;
+        }
+
+        Ok(PsramTiming {
+            clkdiv: divisor as u8,
+            rxdelay: rxdelay as u8,
+            max_select: max_select as u8,
+            min_deselect: min_deselect as u8,
+        })
+    }
+
+    /// Combine the timings for two system clocks into one that is safe at
+    /// either, for use while the clock is being switched between them.
+    ///
+    /// SCK is never faster than either timing allows, CS is never held
+    /// asserted longer, nor deasserted for less time.
+    pub fn covering(&self, other: &Self) -> Self {
+        let rxdelay = match self.clkdiv.cmp(&other.clkdiv) {
+            Ordering::Greater => self.rxdelay,
+            Ordering::Less => other.rxdelay,
+            Ordering::Equal => self.rxdelay.max(other.rxdelay),
+        };
+        PsramTiming {
+            clkdiv: self.clkdiv.max(other.clkdiv),
+            rxdelay,
+            max_select: self.max_select.min(other.max_select),
+            min_deselect: self.min_deselect.max(other.min_deselect),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psram::APS_PARAMS;
+
+    /// Expected `(sys_clock_hz, clkdiv, rxdelay, max_select, min_deselect)`
+    /// for the APS6404L, worked out by hand from its datasheet limits: SCK at
+    /// most 133 MHz, tCEM 8 us and tCPH 18 ns.
+    const APS6404L: [(u32, u8, u8, u8, u8); 12] = [
+        (12_000_000, 1, 1, 1, 0),
+        (48_000_000, 1, 1, 6, 0),
+        (100_000_000, 1, 1, 12, 1),
+        (125_000_000, 2, 2, 15, 2),
+        (133_000_000, 2, 2, 16, 2),
+        (150_000_000, 2, 2, 18, 2),
+        (200_000_000, 2, 2, 25, 3),
+        (250_000_000, 2, 3, 31, 4),
+        (266_000_000, 2, 3, 33, 4),
+        (300_000_000, 3, 3, 37, 4),
+        (333_000_000, 3, 4, 41, 4),
+        (400_000_000, 4, 4, 50, 6),
+    ];
+
+    #[test]
+    fn aps6404l_table() {
+        for (clock_hz, clkdiv, rxdelay, max_select, min_deselect) in APS6404L {
+            assert_eq!(
+                PsramTiming::compute(clock_hz, &APS_PARAMS),
+                Ok(PsramTiming {
+                    clkdiv,
+                    rxdelay,
+                    max_select,
+                    min_deselect,
+                }),
+                "at {clock_hz} Hz"
+            );
+        }
+    }
+
+    #[test]
+    fn max_select_overflow() {
+        // 8 us is 75 lots of 64 cycles at 600 MHz, more than MAX_SELECT holds.
+        assert_eq!(
+            PsramTiming::compute(600_000_000, &APS_PARAMS),
+            Err(PsramError::ClockOutOfRange {
+                clock_hz: 600_000_000
+            })
+        );
+    }
+
+    #[test]
+    fn too_slow_for_tcem() {
+        // Even one lot of 64 cycles is longer than 8 us at 6 MHz.
+        assert!(PsramTiming::compute(6_000_000, &APS_PARAMS).is_err());
+        assert!(PsramTiming::compute(0, &APS_PARAMS).is_err());
+    }
+
+    #[test]
+    fn with_divisor_rejects_fast_sck() {
+        // 266 MHz / 1 and 400 MHz / 3 are both over the 133 MHz limit.
+        assert_eq!(
+            PsramTiming::with_divisor(266_000_000, &APS_PARAMS, 1, 2),
+            Err(PsramError::ClockOutOfRange {
+                clock_hz: 266_000_000
+            })
+        );
+        assert!(PsramTiming::with_divisor(400_000_000, &APS_PARAMS, 3, 4).is_err());
+        assert!(PsramTiming::with_divisor(266_000_000, &APS_PARAMS, 0, 0).is_err());
+        assert_eq!(
+            PsramTiming::with_divisor(266_000_000, &APS_PARAMS, 2, 3),
+            PsramTiming::compute(266_000_000, &APS_PARAMS)
+        );
+    }
+
+    #[test]
+    fn covering_is_safe_at_both_clocks() {
+        let slow = PsramTiming::compute(150_000_000, &APS_PARAMS).unwrap();
+        let fast = PsramTiming::compute(300_000_000, &APS_PARAMS).unwrap();
+        let both = slow.covering(&fast);
+        assert_eq!(both, fast.covering(&slow));
+        assert_eq!(both.clkdiv, fast.clkdiv);
+        assert_eq!(both.rxdelay, fast.rxdelay);
+        assert_eq!(both.max_select, slow.max_select);
+        assert_eq!(both.min_deselect, fast.min_deselect);
+    }
+}